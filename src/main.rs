@@ -1,7 +1,7 @@
 use std::{net::SocketAddr, time::Duration};
 
 use axum::{
-    extract::{Path, State},
+    extract::{ConnectInfo, Path, Query, State},
     http::{HeaderMap, StatusCode},
     response::IntoResponse,
     routing::{get, post},
@@ -10,17 +10,70 @@ use axum::{
 use chrono::{DateTime, Utc};
 use dashmap::DashMap;
 use hmac::{Hmac, Mac};
+use rand::{rngs::StdRng, Rng, SeedableRng};
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
-use sha2::Sha256;
+use sha2::{Digest, Sha256};
 use tokio::{net::TcpListener, time::sleep};
-use tower_http::{cors::{Any, CorsLayer}, trace::TraceLayer};
-use tracing::{error, info, Level};
+use tower_http::{cors::{Any, CorsLayer}, normalize_path::NormalizePath, trace::TraceLayer};
+use tracing::{error, info, warn, Level};
 use uuid::Uuid;
 
 // ===== Models =====
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Invoice identifier. Kept as its string form so strategies that do not
+/// produce UUIDs (nanoid) share the same key type.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+struct InvoiceId(String);
+
+impl std::fmt::Display for InvoiceId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// How new invoice ids are generated (`ID_STRATEGY`).
+#[derive(Debug, Clone, Copy)]
+enum IdStrategy {
+    UuidV4,
+    /// Time-ordered, so ids sort by creation time.
+    UuidV7,
+    /// 21 URL-safe characters.
+    Nanoid,
+}
+
+const NANOID_ALPHABET: &[u8] = b"_-0123456789abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ";
+const NANOID_LEN: usize = 21;
+
+impl IdStrategy {
+    fn generate(self) -> InvoiceId {
+        match self {
+            IdStrategy::UuidV4 => InvoiceId(Uuid::new_v4().to_string()),
+            IdStrategy::UuidV7 => InvoiceId(Uuid::now_v7().to_string()),
+            IdStrategy::Nanoid => {
+                let mut rng = rand::rng();
+                InvoiceId(
+                    (0..NANOID_LEN)
+                        .map(|_| NANOID_ALPHABET[rng.random_range(0..NANOID_ALPHABET.len())] as char)
+                        .collect(),
+                )
+            }
+        }
+    }
+
+    /// Validates an id taken from a path and brings it to the canonical form
+    /// this strategy stores (UUIDs are matched case-insensitively).
+    fn parse(self, raw: &str) -> Option<InvoiceId> {
+        match self {
+            IdStrategy::UuidV4 | IdStrategy::UuidV7 => Uuid::parse_str(raw).ok().map(|u| InvoiceId(u.to_string())),
+            IdStrategy::Nanoid => (raw.len() == NANOID_LEN && raw.bytes().all(|b| NANOID_ALPHABET.contains(&b)))
+                .then(|| InvoiceId(raw.to_string())),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 enum InvoiceStatus {
     Created,
@@ -29,26 +82,571 @@ enum InvoiceStatus {
     Canceled,
     Expired,
     Chargeback,
+    /// Pre-chargeback inquiry on a paid invoice; resolves to `Paid` or `Chargeback`.
+    DisputeOpened,
+    /// Funds held; `POST /invoices/:id/capture` moves it to `Paid`.
+    Authorized,
+    /// Authorization released without a capture.
+    Voided,
+    /// Waiting on a 3DS challenge; `POST /invoices/:id/authenticate` resolves it.
+    PendingAuthentication,
+    /// Held by the acquirer for `process_after_ms` before it becomes `Created`.
+    Queued,
+    /// Held for manual review over `RISK_REVIEW_THRESHOLD`;
+    /// `POST /invoices/:id/review` resolves it.
+    Review,
+}
+
+impl InvoiceStatus {
+    /// Statuses that settle the payment one way or the other; later disputes
+    /// aside, nothing else is scheduled from them.
+    fn is_terminal(&self) -> bool {
+        matches!(
+            self,
+            InvoiceStatus::Paid
+                | InvoiceStatus::Failed
+                | InvoiceStatus::Canceled
+                | InvoiceStatus::Expired
+                | InvoiceStatus::Chargeback
+                | InvoiceStatus::Voided
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum DeclineCode {
+    #[default]
+    GenericDecline,
+    InsufficientFunds,
+    DoNotHonor,
+    LostCard,
+    StolenCard,
+    ExpiredCard,
+    IncorrectCvc,
+    CardVelocityExceeded,
+    ProcessingError,
+}
+
+impl DeclineCode {
+    /// Human-readable `decline_message`.
+    fn message(self) -> &'static str {
+        match self {
+            DeclineCode::GenericDecline => "The card was declined.",
+            DeclineCode::InsufficientFunds => "The card has insufficient funds.",
+            DeclineCode::DoNotHonor => "The issuer declined the payment without giving a reason.",
+            DeclineCode::LostCard => "The card was reported lost.",
+            DeclineCode::StolenCard => "The card was reported stolen.",
+            DeclineCode::ExpiredCard => "The card has expired.",
+            DeclineCode::IncorrectCvc => "The card's security code is incorrect.",
+            DeclineCode::CardVelocityExceeded => "The card exceeded its usage limits.",
+            DeclineCode::ProcessingError => "An error occurred while processing the card.",
+        }
+    }
+}
+
+/// A named webhook target from the `CONSUMERS` registry.
+#[derive(Debug, Clone, Deserialize)]
+struct Consumer {
+    url: String,
+    /// Signs this consumer's webhooks instead of the configured secrets.
+    #[serde(default)]
+    secret: Option<String>,
+    /// Extra static headers; may not override the ones the acquirer sets.
+    #[serde(default)]
+    headers: std::collections::BTreeMap<String, String>,
+}
+
+/// Limits for one tenant (`TENANT_QUOTAS`); an unset limit is unlimited.
+#[derive(Debug, Clone, Copy, Deserialize)]
+struct TenantQuota {
+    #[serde(default)]
+    rate_per_min: Option<u32>,
+    #[serde(default)]
+    daily_quota: Option<u32>,
+}
+
+/// Invoices a tenant created in the current minute and UTC day.
+#[derive(Debug, Clone, Copy)]
+struct TenantUsage {
+    minute_start: DateTime<Utc>,
+    minute_count: u32,
+    day: chrono::NaiveDate,
+    day_count: u32,
+}
+
+impl TenantUsage {
+    fn new(now: DateTime<Utc>) -> Self {
+        let mut usage = Self { minute_start: now, minute_count: 0, day: now.date_naive(), day_count: 0 };
+        usage.roll(now);
+        usage
+    }
+
+    /// Starts new windows once the clock minute or day has moved on.
+    fn roll(&mut self, now: DateTime<Utc>) {
+        let minute_start = DateTime::from_timestamp(now.timestamp() - now.timestamp().rem_euclid(60), 0).unwrap_or(now);
+        if minute_start != self.minute_start {
+            self.minute_start = minute_start;
+            self.minute_count = 0;
+        }
+        if now.date_naive() != self.day {
+            self.day = now.date_naive();
+            self.day_count = 0;
+        }
+    }
+
+    fn minute_resets_at(&self) -> DateTime<Utc> {
+        self.minute_start + chrono::Duration::minutes(1)
+    }
+
+    fn day_resets_at(&self) -> DateTime<Utc> {
+        self.day.succ_opt().unwrap_or(self.day).and_time(chrono::NaiveTime::MIN).and_utc()
+    }
+}
+
+/// One row of `GET /admin/tenants`.
+#[derive(Debug, Serialize)]
+struct TenantUsageReport {
+    tenant: String,
+    rate_per_min: Option<u32>,
+    daily_quota: Option<u32>,
+    used_this_minute: u32,
+    used_today: u32,
+    minute_resets_at: DateTime<Utc>,
+    day_resets_at: DateTime<Utc>,
+}
+
+/// Headers set by `deliver` that consumer headers may not replace.
+const RESERVED_WEBHOOK_HEADERS: &[&str] = &[
+    "content-type", "content-digest", "authorization", "accept", "expect", "x-event", "x-event-id", "x-signature", "x-timestamp", "x-acquirer-id",
+];
+
+/// Parses `CONSUMERS`, a JSON object of name → `Consumer`.
+fn parse_consumers(raw: &str) -> std::collections::HashMap<String, Consumer> {
+    let consumers: std::collections::HashMap<String, Consumer> =
+        serde_json::from_str(raw).expect("CONSUMERS must be a JSON object of name -> {url, secret, headers}");
+    for (name, consumer) in &consumers {
+        for (header, value) in &consumer.headers {
+            assert!(
+                !RESERVED_WEBHOOK_HEADERS.iter().any(|r| r.eq_ignore_ascii_case(header)),
+                "CONSUMERS: {} sets reserved header {}", name, header
+            );
+            assert!(
+                axum::http::HeaderName::from_bytes(header.as_bytes()).is_ok()
+                    && axum::http::HeaderValue::from_str(value).is_ok(),
+                "CONSUMERS: {} has an invalid header {}", name, header
+            );
+        }
+    }
+    consumers
+}
+
+/// What the shopper still has to do before the payment can proceed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RequiresAction {
+    #[serde(rename = "type")]
+    kind: String,
+    challenge_url: String,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct Invoice {
-    id: Uuid,
+    id: InvoiceId,
     amount: u64,
     currency: String,
     status: InvoiceStatus,
     webhook_url: String,
+    /// Every destination, primary (`webhook_url`) first, when created with
+    /// `webhook_urls`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    webhook_urls: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    webhook_delivery_mode: Option<WebhookDeliveryMode>,
     created_at: DateTime<Utc>,
+    /// Bumped by every change to the invoice (status, webhook URL, notes...).
+    #[serde(default)]
+    updated_at: DateTime<Utc>,
     metadata: serde_json::Value,
+    /// Client-supplied reference, unique across invoices.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    external_id: Option<String>,
+    /// Registered consumer the webhooks go to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    consumer: Option<String>,
+    /// `X-Tenant-Id` of the creating request.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tenant: Option<String>,
+    /// Reported if the invoice ends up `failed`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    decline_code: Option<DeclineCode>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    simulation: Option<Simulation>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    webhook_chunked: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    webhook_accept: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    webhook_expect_continue: Option<bool>,
+    /// Fields the `ENRICH_URL` service merged into the create request.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    enrichment: Option<serde_json::Value>,
+    /// Formatting overrides; only kept when valid, so `None` means the server
+    /// default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    locale: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tz: Option<String>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    require_signature_echo: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    emit_only_if_amount_gte: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    webhook_deadline_ms: Option<u64>,
+    /// Per-invoice retry budget, already clamped to the server ceiling.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_retries: Option<u32>,
+    /// Hold placed at authorization, when it differs from `amount`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    auth_amount: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sign_timestamp_offset_secs: Option<i64>,
+    /// Set while a 3DS challenge is outstanding (`require_3ds`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    requires_action: Option<RequiresAction>,
+    /// Fraud/risk score, 0–100.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    risk_score: Option<u8>,
+    /// Status an approved review resumes to; set while the invoice is held
+    /// for (or headed to) `Review`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    review_resume_status: Option<InvoiceStatus>,
+    /// Amount actually captured (may be less than `amount`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    captured_amount: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    capture_expires_in_ms: Option<u64>,
+    /// Replaces `expired` when the invoice expires unpaid.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expire_to_status: Option<InvoiceStatus>,
+    /// Set when the invoice becomes `Authorized` with `capture_expires_in_ms`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    capture_expires_at: Option<DateTime<Utc>>,
+    /// When the next scheduled webhook fires; `null` once nothing more is
+    /// scheduled (terminal, or waiting on an API call such as a capture).
+    #[serde(default)]
+    next_emit_at: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    process_after_ms: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sink_attempt_profile: Option<Vec<SinkAttempt>>,
+    /// Settlement batch the invoice joined when it was paid.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    batch_id: Option<String>,
+    /// What the pending emit will apply, kept so a restored snapshot can
+    /// reschedule it; cleared once the invoice leaves `created`/`queued`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    scheduled_emit: Option<ScheduledEmit>,
+    /// When `invoice.closed` went out (`EMIT_CLOSED_EVENT`); it never goes out twice.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    closed_at: Option<DateTime<Utc>>,
+    /// Why the server expired the invoice on its own, when it did.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expired_reason: Option<ExpiredReason>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    emit_on_view: bool,
+    /// First read through `GET /invoices/:id` or the checkout page, when
+    /// `emit_on_view` sent `invoice.viewed`; it never goes out twice.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    viewed_at: Option<DateTime<Utc>>,
+    /// Overrides `WEBHOOK_BODY_TEMPLATE`; validated at creation.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    webhook_body_template: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    webhook_field_order: Option<Vec<String>>,
+    /// Every status change, oldest first, starting with the creation itself.
+    #[serde(default)]
+    history: Vec<Transition>,
+    /// Free-text annotations left by test authors.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    notes: Vec<Note>,
+}
+
+impl Invoice {
+    /// Every URL the invoice's webhooks may go to: `webhook_urls`, or just
+    /// `webhook_url` when it wasn't created with several.
+    fn webhook_targets(&self) -> &[String] {
+        if self.webhook_urls.is_empty() {
+            std::slice::from_ref(&self.webhook_url)
+        } else {
+            &self.webhook_urls
+        }
+    }
+}
+
+/// How an invoice with several `webhook_urls` uses them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum WebhookDeliveryMode {
+    /// Every event goes to every URL.
+    #[default]
+    Fanout,
+    /// Every event goes to the first URL; only once its retries are used up
+    /// does the next one get it (active/passive consumers).
+    Failover,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ExpiredReason {
+    /// Its scheduled emit would have outlived `MAX_SCHEDULED_TASK_LIFETIME_SECS`.
+    SchedulerCeiling,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ScheduledEmit {
+    status: InvoiceStatus,
+    /// Emit delay in ms, before `TIME_SCALE`; for a queued invoice it starts
+    /// once the invoice is processed.
+    delay_ms: u64,
+    /// Send `invoice.updated` when a queued invoice is processed.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    notify_on_process: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Note {
+    at: DateTime<Utc>,
+    text: String,
 }
 
 #[derive(Debug, Clone, Deserialize)]
+struct CreateNote {
+    text: String,
+}
+
+/// Caps on how wide and deep `metadata` may be, and keys it must have; unset
+/// means unlimited.
+#[derive(Debug, Clone, Default)]
+struct MetadataLimits {
+    /// Object keys counted across the whole value, nested ones included.
+    max_keys: Option<usize>,
+    /// Nesting of objects and arrays; a flat object is depth 1.
+    max_depth: Option<usize>,
+    /// Top-level keys every invoice must tag itself with.
+    required_keys: Vec<String>,
+}
+
+impl MetadataLimits {
+    /// Walks `metadata` and reports the first violation as an error naming its
+    /// path (`metadata.a.b[2]`).
+    fn check(&self, metadata: &serde_json::Value) -> Result<(), ApiError> {
+        let mut keys = 0;
+        self.walk(metadata, "metadata".to_string(), 0, &mut keys)?;
+        let missing: Vec<&String> = self.required_keys.iter().filter(|key| metadata.get(key.as_str()).is_none()).collect();
+        if !missing.is_empty() {
+            let names: Vec<&str> = missing.iter().map(|key| key.as_str()).collect();
+            return Err(ApiError::new(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "missing_metadata_keys",
+                format!("metadata is missing required keys: {}", names.join(", ")),
+            )
+            .with_detail("missing", serde_json::json!(missing)));
+        }
+        Ok(())
+    }
+
+    fn walk(&self, value: &serde_json::Value, path: String, depth: usize, keys: &mut usize) -> Result<(), ApiError> {
+        let children: Vec<(String, &serde_json::Value)> = match value {
+            serde_json::Value::Object(map) => map.iter().map(|(k, v)| (format!("{}.{}", path, k), v)).collect(),
+            serde_json::Value::Array(items) => items.iter().enumerate().map(|(i, v)| (format!("{}[{}]", path, i), v)).collect(),
+            _ => return Ok(()),
+        };
+        if let Some(max) = self.max_depth.filter(|max| depth + 1 > *max) {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "metadata_too_deep",
+                format!("metadata nests deeper than {} levels at {}", max, path),
+            )
+            .with_detail("path", path));
+        }
+        let is_object = value.is_object();
+        for (child_path, child) in children {
+            if is_object {
+                *keys += 1;
+                if let Some(max) = self.max_keys.filter(|max| *keys > *max) {
+                    return Err(ApiError::new(
+                        StatusCode::BAD_REQUEST,
+                        "metadata_too_many_keys",
+                        format!("metadata has more than {} keys; the first one over is {}", max, child_path),
+                    )
+                    .with_detail("path", child_path));
+                }
+            }
+            self.walk(child, child_path, depth + 1, keys)?;
+        }
+        Ok(())
+    }
+}
+
+/// Terminal statuses an unpaid invoice may expire into (`expire_to_status`).
+const EXPIRY_STATUSES: &[InvoiceStatus] = &[InvoiceStatus::Expired, InvoiceStatus::Canceled];
+
+const MAX_NOTES_PER_INVOICE: usize = 50;
+const MAX_PROCESS_AFTER_MS: u64 = 3_600_000;
+/// Ceiling for delays sampled from an `emit_delay_model`.
+const MAX_EMIT_AFTER_MS: u64 = 3_600_000;
+const MAX_EXTERNAL_ID_LEN: usize = 255;
+const MAX_SINK_PROFILE_STEPS: usize = 50;
+const MAX_SINK_DELAY_MS: u64 = 60_000;
+const MAX_WEBHOOK_URLS: usize = 5;
+
+/// Bounds for `TIME_SCALE`; values outside are clamped.
+const MIN_TIME_SCALE: f64 = 0.01;
+const MAX_TIME_SCALE: f64 = 1000.0;
+const MAX_NOTE_CHARS: usize = 500;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Transition {
+    /// Position in the server-wide event log.
+    seq: u64,
+    at: DateTime<Utc>,
+    /// `None` for the creation entry.
+    from: Option<InvoiceStatus>,
+    to: InvoiceStatus,
+}
+
+/// A transition as seen in the server-wide, ordered event log.
+#[derive(Debug, Clone, Serialize)]
+struct InvoiceEvent {
+    cursor: String,
+    seq: u64,
+    at: DateTime<Utc>,
+    invoice_id: InvoiceId,
+    from: Option<InvoiceStatus>,
+    to: InvoiceStatus,
+}
+
+/// Paid invoices for one webhook URL, announced together with a single
+/// `settlement.batch.closed` when the batch window ends.
+#[derive(Debug, Clone, Serialize)]
+struct SettlementBatch {
+    id: String,
+    webhook_url: String,
+    status: BatchStatus,
+    opened_at: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    closed_at: Option<DateTime<Utc>>,
+    count: u64,
+    /// Settled amount per currency (`captured_amount` for partial captures).
+    totals: std::collections::BTreeMap<String, u64>,
+    invoice_ids: Vec<InvoiceId>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum BatchStatus {
+    Open,
+    Closed,
+}
+
+/// Random decisions taken for an invoice, echoed back so a flaky run can be
+/// reproduced by resubmitting the same `seed`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Simulation {
+    seed: u64,
+    chosen_outcome: InvoiceStatus,
+    applied_jitter_ms: u64,
+    /// Drawn from `emit_delay_model`, before jitter.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sampled_delay_ms: Option<u64>,
+}
+
+/// Distribution the emit delay is drawn from (`emit_delay_model`). Parameters
+/// are in milliseconds; which ones are required depends on `model`. Kept flat
+/// rather than as a tagged enum, whose buffering can't read numbers under
+/// serde_json's `arbitrary_precision`.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct DelayModel {
+    model: DelayModelKind,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    min: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    mean: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    stddev: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    lambda: Option<f64>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum DelayModelKind {
+    /// Always `emit_after_ms`.
+    Fixed,
+    /// `min` to `max`.
+    Uniform,
+    /// `mean`, `stddev`.
+    Normal,
+    /// Rate `lambda`, so a mean delay of `1 / lambda`.
+    Exponential,
+}
+
+impl DelayModel {
+    fn validate(&self) -> Result<(), &'static str> {
+        let ok = |v: Option<f64>| v.is_some_and(|v| v.is_finite() && v >= 0.0);
+        match self.model {
+            DelayModelKind::Fixed => Ok(()),
+            DelayModelKind::Uniform if ok(self.min) && ok(self.max) && self.min <= self.max => Ok(()),
+            DelayModelKind::Uniform => Err("uniform needs 0 <= min <= max"),
+            DelayModelKind::Normal if ok(self.mean) && ok(self.stddev) => Ok(()),
+            DelayModelKind::Normal => Err("normal needs a non-negative mean and stddev"),
+            DelayModelKind::Exponential if self.lambda.is_some_and(|l| l.is_finite() && l > 0.0) => Ok(()),
+            DelayModelKind::Exponential => Err("exponential needs lambda > 0"),
+        }
+    }
+
+    /// Draws a delay, clamped to `[0, MAX_EMIT_AFTER_MS]`. Assumes `validate`
+    /// passed.
+    fn sample(&self, emit_after_ms: u64, rng: &mut StdRng) -> u64 {
+        let param = |v: Option<f64>| v.unwrap_or_default();
+        let ms = match self.model {
+            DelayModelKind::Fixed => emit_after_ms as f64,
+            DelayModelKind::Uniform => param(self.min) + (param(self.max) - param(self.min)) * rng.random::<f64>(),
+            DelayModelKind::Normal => {
+                // Box-Muller; `1 - u` keeps the log argument in (0, 1].
+                let (u1, u2) = (1.0 - rng.random::<f64>(), rng.random::<f64>());
+                param(self.mean) + param(self.stddev) * (-2.0 * u1.ln()).sqrt() * (std::f64::consts::TAU * u2).cos()
+            }
+            DelayModelKind::Exponential => -(1.0 - rng.random::<f64>()).ln() / param(self.lambda),
+        };
+        ms.clamp(0.0, MAX_EMIT_AFTER_MS as f64) as u64
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct CreateInvoice {
     amount: u64,
     #[serde(default = "default_currency")] 
     currency: String,
+    /// Falls back to `DEFAULT_WEBHOOK_URL` when omitted or blank.
+    #[serde(default)]
     webhook_url: String,
 
+    /// Name from the `CONSUMERS` registry; supplies the URL, secret and extra
+    /// headers instead of `webhook_url`.
+    #[serde(default)]
+    consumer: Option<String>,
+
+    /// Several destinations instead of `webhook_url`, used per
+    /// `webhook_delivery_mode`; the first one is the primary.
+    #[serde(default)]
+    webhook_urls: Option<Vec<String>>,
+
+    #[serde(default)]
+    webhook_delivery_mode: Option<WebhookDeliveryMode>,
+
     /// Milliseconds to wait before emitting the webhook.
     #[serde(default = "default_emit_after_ms")] 
     emit_after_ms: u64,
@@ -56,12 +654,173 @@ struct CreateInvoice {
     /// Final status to emit in the webhook.
     emit_status: EmitStatus,
 
+    /// Upper bound (inclusive) of a random delay added to `emit_after_ms`.
+    #[serde(default)]
+    emit_jitter_ms: u64,
+
+    /// Draws the delay from a distribution instead of using `emit_after_ms`
+    /// as is; jitter still applies on top.
+    #[serde(default)]
+    emit_delay_model: Option<DelayModel>,
+
+    /// Expect the consumer to send the `X-Signature` it received back in
+    /// `X-Signature-Echo`, and record whether it matched.
+    #[serde(default)]
+    require_signature_echo: bool,
+
+    /// Seed for the per-invoice RNG; drawn from the server RNG when omitted.
+    #[serde(default)]
+    seed: Option<u64>,
+
     /// Arbitrary extra fields you want echoed back.
     #[serde(default)]
     metadata: serde_json::Value,
+
+    /// Only deliver webhooks when `amount` is at least this value; the status
+    /// still transitions either way.
+    #[serde(default)]
+    emit_only_if_amount_gte: Option<u64>,
+
+    /// Run the URL-verification handshake against `webhook_url` before accepting
+    /// the invoice (skipped once the URL has passed it).
+    #[serde(default)]
+    verify_webhook_url: bool,
+
+    /// Stream the webhook body with `Transfer-Encoding: chunked` instead of a
+    /// fixed `Content-Length`.
+    #[serde(default)]
+    webhook_chunked: bool,
+
+    /// `Accept` sent with this invoice's webhooks; overrides `WEBHOOK_ACCEPT`.
+    #[serde(default)]
+    webhook_accept: Option<String>,
+
+    /// Send `Expect: 100-continue`; overrides `WEBHOOK_EXPECT_CONTINUE`.
+    #[serde(default)]
+    webhook_expect_continue: Option<bool>,
+
+    /// Locale for this invoice's formatted fields; overrides `LOCALE`. An
+    /// unsupported value falls back to the server default.
+    #[serde(default)]
+    locale: Option<String>,
+
+    /// UTC offset (`-03:00`, `UTC`) for this invoice's local timestamps;
+    /// overrides `TIMEZONE`, with the same fallback as `locale`.
+    #[serde(default)]
+    tz: Option<String>,
+
+    /// Test mode: reject the request with `402` as a synchronous decline instead
+    /// of creating an invoice.
+    #[serde(default)]
+    decline_at_create: bool,
+
+    /// Why the payment is declined, reported with `decline_at_create` and on
+    /// `failed` webhooks. Defaults to `generic_decline`.
+    #[serde(default)]
+    decline_code: Option<DeclineCode>,
+
+    /// Give up on a webhook (retries included) once this many ms have passed
+    /// since it was first emitted.
+    #[serde(default)]
+    webhook_deadline_ms: Option<u64>,
+
+    /// Fraud/risk score, 0–100. Above `RISK_REVIEW_THRESHOLD`, the scheduled
+    /// emit holds the invoice in `Review` instead.
+    #[serde(default)]
+    risk_score: Option<u8>,
+
+    /// With `emit_status=authorized`, void the authorization if it isn't
+    /// captured within this many ms of being authorized.
+    #[serde(default)]
+    capture_expires_in_ms: Option<u64>,
+
+    /// Where the invoice goes when it expires unpaid (`emit_status=expired`,
+    /// or a mass expiry): `expired` (default) or `canceled`.
+    #[serde(default)]
+    expire_to_status: Option<InvoiceStatus>,
+
+    /// Amount held at authorization (e.g. a hotel deposit); must be at least
+    /// `amount`. Defaults to `amount`.
+    #[serde(default)]
+    auth_amount: Option<u64>,
+
+    /// Test mode: skew the signed `X-Timestamp` by this many seconds (negative
+    /// for the past) to exercise the consumer's tolerance window.
+    #[serde(default)]
+    sign_timestamp_offset_secs: Option<i64>,
+
+    /// The scheduled emit moves the invoice to `PendingAuthentication` with a
+    /// 3DS challenge instead of `emit_status`; `/authenticate` settles it.
+    #[serde(default)]
+    require_3ds: bool,
+
+    /// Overrides `WEBHOOK_MAX_RETRIES` for this invoice, clamped to
+    /// `WEBHOOK_MAX_RETRIES_CEILING`.
+    #[serde(default)]
+    max_retries: Option<u32>,
+
+    /// Keep the invoice `Queued` for this many ms before it becomes `Created`;
+    /// the `emit_after_ms` countdown starts only then.
+    #[serde(default)]
+    process_after_ms: Option<u64>,
+
+    /// Deliver `invoice.queued` on creation and `invoice.updated` when the
+    /// invoice leaves the queue. Only meaningful with `process_after_ms`.
+    #[serde(default)]
+    emit_queued_webhook: bool,
+
+    /// Send `invoice.viewed` the first time the invoice is read.
+    #[serde(default)]
+    emit_on_view: bool,
+
+    /// Send this instead of the standard payload; see `BodyTemplate`.
+    #[serde(default)]
+    webhook_body_template: Option<String>,
+
+    /// Top-level fields of the standard payload to put first, in this order;
+    /// see `render_in_order`.
+    #[serde(default)]
+    webhook_field_order: Option<Vec<String>>,
+
+    /// Client reference that must be unique: a second create with the same
+    /// value gets `409` instead of a replay.
+    #[serde(default)]
+    external_id: Option<String>,
+
+    /// Test mode: how the built-in `/_sink` answers each delivery attempt of
+    /// this invoice's webhooks, in order.
+    #[serde(default)]
+    sink_attempt_profile: Option<Vec<SinkAttempt>>,
+
+    /// Test mode: deliver the webhook before answering and report how it
+    /// went (`CreateInvoiceResponse::delivery`). Needs an immediate emit.
+    #[serde(default)]
+    deliver_sync: bool,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+/// One scripted `/_sink` response.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SinkAttempt {
+    status: u16,
+    /// Wait this long before answering.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    delay_ms: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    retry_after_secs: Option<u64>,
+}
+
+fn is_zero(n: &u64) -> bool {
+    *n == 0
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct CaptureRequest {
+    /// Partial capture; defaults to the invoice `amount`.
+    #[serde(default)]
+    amount: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")] 
 enum EmitStatus {
     Paid,
@@ -69,222 +828,6120 @@ enum EmitStatus {
     Canceled,
     Expired,
     Chargeback,
+    /// Hold the funds until captured (or until `capture_expires_in_ms` voids them).
+    Authorized,
+    /// Pick one of the final outcomes above (never `authorized`) using the
+    /// invoice's seeded RNG.
+    Random,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct UpdateInvoice {
+    #[serde(default)]
+    webhook_url: Option<String>,
 }
 
 #[derive(Debug, Serialize)]
 struct CreateInvoiceResponse {
-    id: Uuid,
+    id: InvoiceId,
     status: InvoiceStatus,
     amount: u64,
+    amount_formatted: String,
     currency: String,
     created_at: DateTime<Utc>,
     webhook_url: String,
     checkout_url: String,
     metadata: serde_json::Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    external_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    simulation: Option<Simulation>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    requires_action: Option<RequiresAction>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    delivery: Option<SyncDelivery>,
+}
+
+/// How a `deliver_sync` webhook went, as far as it got before the response.
+#[derive(Debug, Serialize)]
+struct SyncDelivery {
+    /// Whether the delivery was done (delivered or given up) within one
+    /// attempt's timeout; if not, any retries carry on in the background.
+    completed: bool,
+    attempts: Vec<DeliveryRecord>,
+}
+
+/// Body of `invoice.viewed`, much smaller than the status webhooks'.
+#[derive(Debug, Serialize)]
+struct ViewedPayload {
+    event: &'static str,
+    event_id: String,
+    id: InvoiceId,
+    status: InvoiceStatus,
+    viewed_at: DateTime<Utc>,
+    /// `api` for `GET /invoices/:id`, `checkout` for the hosted page.
+    via: &'static str,
+    viewer: Viewer,
+}
+
+#[derive(Debug, Serialize)]
+struct Viewer {
+    /// First `X-Forwarded-For` hop, else the peer address; masked per
+    /// `VIEWER_MASKING`.
+    ip: Option<String>,
+    user_agent: Option<String>,
+}
+
+impl Viewer {
+    fn from_request(masking: ViewerMasking, headers: &HeaderMap, peer: Option<SocketAddr>) -> Self {
+        let forwarded = headers
+            .get("x-forwarded-for")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.split(',').next())
+            .and_then(|hop| hop.trim().parse().ok());
+        let user_agent = headers
+            .get(axum::http::header::USER_AGENT)
+            .map(|v| String::from_utf8_lossy(v.as_bytes()).into_owned());
+        Viewer {
+            ip: forwarded.or(peer.map(|p| p.ip())).map(|ip| masking.ip(ip)),
+            user_agent: user_agent.map(|ua| masking.user_agent(ua)),
+        }
+    }
 }
 
 #[derive(Debug, Serialize)]
 struct WebhookPayload {
     event: &'static str,             // e.g. "invoice.updated"
-    id: Uuid,
+    /// Same across retries, distinct per transition and event type.
+    event_id: String,
+    /// Global `seq` of the transition that produced `status`; strictly
+    /// increasing per invoice, so consumers can spot reordered deliveries.
+    seq: u64,
+    id: InvoiceId,
     status: InvoiceStatus,
     amount: u64,
+    amount_formatted: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    auth_amount: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    captured_amount: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    risk_score: Option<u8>,
+    /// Only on `failed`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    decline_code: Option<DeclineCode>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    decline_message: Option<&'static str>,
     currency: String,
     emitted_at: DateTime<Utc>,
-    metadata: serde_json::Value,
+    /// `emitted_at` in the invoice's `tz`.
+    emitted_at_local: DateTime<chrono::FixedOffset>,
+    /// Locale `amount_formatted` was rendered in.
+    locale: String,
+    /// Offset `emitted_at_local` is expressed in, as `+HH:MM`.
+    tz: String,
+    /// Absent when the object is over `METADATA_INLINE_MAX_BYTES`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metadata: Option<serde_json::Value>,
+    /// Where to fetch `metadata` when it was left out.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metadata_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    requires_action: Option<RequiresAction>,
+    /// Only on `invoice.closed`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    lifecycle: Option<Lifecycle>,
+    /// When the transition behind `status` happened; only on
+    /// `invoice.redelivered`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    occurred_at: Option<DateTime<Utc>>,
+    /// Deprecated aliases, sent only with `WEBHOOK_COMPAT_FIELDS`.
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    compat: Option<CompatFields>,
 }
 
-fn default_currency() -> String { "BRL".to_string() }
-fn default_emit_after_ms() -> u64 { 5_000 }
-
-// ===== State =====
+/// `CompatFields` keys, in declaration order.
+const COMPAT_FIELD_NAMES: &[&str] = &["type", "invoice_id", "state"];
 
-#[derive(Clone)]
-struct AppState {
-    invoices: std::sync::Arc<DashMap<Uuid, Invoice>>, 
-    idempotency: std::sync::Arc<DashMap<String, Uuid>>, 
-    client: Client,
-    webhook_secret: String,
+/// Older names of renamed `WebhookPayload` fields, for consumers that have not
+/// migrated yet. Each mirrors the field noted next to it.
+#[derive(Debug, Serialize)]
+struct CompatFields {
+    /// `event`
+    #[serde(rename = "type")]
+    kind: &'static str,
+    /// `id`
+    invoice_id: InvoiceId,
+    /// `status`
+    state: InvoiceStatus,
 }
 
-// ===== Helpers =====
+/// End-of-life summary carried by `invoice.closed`.
+#[derive(Debug, Clone, Serialize)]
+struct Lifecycle {
+    created_at: DateTime<Utc>,
+    closed_at: DateTime<Utc>,
+    /// Transitions so far, the creation included.
+    history_length: usize,
+    /// Webhook delivery attempts made for the invoice before this one.
+    total_attempts: usize,
+}
 
-fn hmac_hex(secret: &str, body: &str) -> String {
-    let mut mac = <Hmac<Sha256>>::new_from_slice(secret.as_bytes()).expect("hmac key");
-    mac.update(body.as_bytes());
-    hex::encode(mac.finalize().into_bytes())
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum DeliveryOutcome {
+    Delivered,
+    /// Attempt failed and another one is scheduled.
+    Failed,
+    /// Attempt failed and no further attempt will be made.
+    DeadLettered,
+    /// Retry dropped because the invoice reached a newer terminal status.
+    Superseded,
 }
 
-fn map_emit_status(s: &EmitStatus) -> InvoiceStatus {
-    match s {
-        EmitStatus::Paid => InvoiceStatus::Paid,
-        EmitStatus::Failed => InvoiceStatus::Failed,
-        EmitStatus::Canceled => InvoiceStatus::Canceled,
-        EmitStatus::Expired => InvoiceStatus::Expired,
-        EmitStatus::Chargeback => InvoiceStatus::Chargeback,
+impl DeliveryOutcome {
+    const ALL: [DeliveryOutcome; 4] =
+        [DeliveryOutcome::Delivered, DeliveryOutcome::Failed, DeliveryOutcome::DeadLettered, DeliveryOutcome::Superseded];
+
+    fn as_str(self) -> &'static str {
+        match self {
+            DeliveryOutcome::Delivered => "delivered",
+            DeliveryOutcome::Failed => "failed",
+            DeliveryOutcome::DeadLettered => "dead_lettered",
+            DeliveryOutcome::Superseded => "superseded",
+        }
     }
 }
 
-// ===== Routes =====
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum DeadLetterReason {
+    RetriesExhausted,
+    /// The next retry would start after the invoice's `webhook_deadline_ms`.
+    DeadlineExceeded,
+    /// The URL already had `WEBHOOK_MAX_OUTSTANDING_PER_URL` deliveries
+    /// queued or in flight; nothing was sent.
+    DestinationOverloaded,
+    /// A gRPC consumer answered with a status that retrying can't fix.
+    NotRetryable,
+}
 
-#[tokio::main]
-async fn main() {
-    dotenvy::dotenv().ok();
+/// One webhook delivery attempt.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct DeliveryRecord {
+    invoice_id: InvoiceId,
+    event: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    event_id: Option<String>,
+    attempt: u32,
+    /// Retry budget in effect for this delivery.
+    max_retries: u32,
+    at: DateTime<Utc>,
+    url: String,
+    host: String,
+    outcome: DeliveryOutcome,
+    status_code: Option<u16>,
+    duration_ms: u64,
+    error: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    dead_letter_reason: Option<DeadLetterReason>,
+    /// Whether a 2xx body matched `WEBHOOK_RETRY_ON_BODY_REGEX`; unset when
+    /// the regex isn't configured or the status wasn't 2xx.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    body_retry_match: Option<bool>,
+    /// `codes` of the `WEBHOOK_RETRY_RULES` entry that decided this failed
+    /// attempt's retry, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    retry_rule: Option<String>,
+    /// With `require_signature_echo`, whether a 2xx response echoed the
+    /// exact `X-Signature` sent; unset otherwise.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    signature_confirmed: Option<bool>,
+    /// Fault the chaos profile injected instead of sending the attempt.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    chaos: Option<ChaosFault>,
+}
 
-    tracing_subscriber::fmt()
-        .with_max_level(Level::INFO)
-        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
-        .init();
+/// One attempt as shown by `GET /admin/recent`.
+#[derive(Debug, Clone, Serialize)]
+struct RecentDelivery {
+    at: DateTime<Utc>,
+    invoice_id: InvoiceId,
+    event: String,
+    attempt: u32,
+    url: String,
+    outcome: DeliveryOutcome,
+    status_code: Option<u16>,
+    duration_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    headers: std::collections::BTreeMap<String, String>,
+    /// First `RECENT_PREVIEW_CHARS` characters of the body.
+    payload_preview: String,
+}
 
-    let state = AppState {
-        invoices: std::sync::Arc::new(DashMap::new()),
-        idempotency: std::sync::Arc::new(DashMap::new()),
-        client: Client::new(),
-        webhook_secret: std::env::var("ACQ_WEBHOOK_SECRET").unwrap_or_else(|_| "dev_secret".into()),
+const RECENT_PREVIEW_CHARS: usize = 512;
+
+/// A dead-lettered webhook, kept with what it takes to send it again.
+#[derive(Debug, Clone)]
+struct DeadLetter {
+    invoice_id: InvoiceId,
+    event: &'static str,
+    url: String,
+    host: String,
+    at: DateTime<Utc>,
+    /// As passed to `deliver`, before any JWT wrapping.
+    body: String,
+    opts: DeliveryOptions,
+}
+
+/// Oldest dead letters are dropped past this.
+const MAX_DEAD_LETTERS: usize = 10_000;
+
+#[derive(Debug, Deserialize)]
+struct DeadLetterFilter {
+    #[serde(default)]
+    host: Option<String>,
+    /// Dead-lettered at or after this instant.
+    #[serde(default)]
+    since: Option<DateTime<Utc>>,
+    /// Dead-lettered before this instant.
+    #[serde(default)]
+    until: Option<DateTime<Utc>>,
+}
+
+impl DeadLetterFilter {
+    fn matches(&self, letter: &DeadLetter) -> bool {
+        self.since.is_none_or(|t| letter.at >= t)
+            && self.until.is_none_or(|t| letter.at < t)
+            && self.host.as_deref().is_none_or(|h| h == letter.host)
+    }
+}
+
+/// One bulk redrive and how its webhooks fared.
+#[derive(Debug, Clone, Serialize)]
+struct RedriveJob {
+    id: String,
+    started_at: DateTime<Utc>,
+    requeued: u64,
+    delivered: u64,
+    /// Dead-lettered again (they are back in the queue) or superseded.
+    failed: u64,
+    pending: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    finished_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RecentQuery {
+    limit: Option<usize>,
+}
+
+/// One distinct event in `GET /invoices/:id/events-emitted`.
+#[derive(Debug, Serialize)]
+struct EmittedEvent {
+    event_id: String,
+    event: String,
+    first_attempt_at: DateTime<Utc>,
+    attempts: u32,
+    /// Retry budget in effect (after the first attempt).
+    max_retries: u32,
+    /// Outcome of the latest attempt.
+    outcome: DeliveryOutcome,
+    status_code: Option<u16>,
+    /// URLs that took it, for invoices with `webhook_urls`: after a failover,
+    /// the standby that finally did.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    delivered_to: Vec<String>,
+}
+
+/// Filters shared by `GET /admin/deliveries` and `/admin/deliveries.csv`.
+#[derive(Debug, Deserialize)]
+struct DeliveryFilter {
+    since: Option<DateTime<Utc>>,
+    until: Option<DateTime<Utc>>,
+    host: Option<String>,
+    outcome: Option<DeliveryOutcome>,
+}
+
+impl DeliveryFilter {
+    fn matches(&self, rec: &DeliveryRecord) -> bool {
+        self.since.is_none_or(|t| rec.at >= t)
+            && self.until.is_none_or(|t| rec.at < t)
+            && self.host.as_deref().is_none_or(|h| h == rec.host)
+            && self.outcome.is_none_or(|o| o == rec.outcome)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct DeliveriesPageQuery {
+    /// `<unix_ms>_<n>` as returned in `next_cursor`; omit to start from the
+    /// oldest attempt.
+    #[serde(default)]
+    cursor: Option<String>,
+    #[serde(default = "default_events_limit")]
+    limit: usize,
+}
+
+/// A delivery log entry with its position, for `GET /admin/deliveries`.
+#[derive(Debug, Serialize)]
+struct DeliveryEntry {
+    cursor: String,
+    #[serde(flatten)]
+    record: DeliveryRecord,
+}
+
+#[derive(Debug, Serialize)]
+struct DeliveriesPage {
+    deliveries: Vec<DeliveryEntry>,
+    /// Pass back as `cursor` for the next page. Covers every attempt examined,
+    /// filtered out or not, and stays unchanged when there are none left.
+    next_cursor: Option<String>,
+}
+
+/// Rows rendered per read-lock acquisition when streaming the delivery CSV.
+const CSV_BATCH_ROWS: usize = 500;
+
+#[derive(Debug, Deserialize)]
+struct DeliveryStatsQuery {
+    #[serde(default = "default_stats_window_secs")]
+    window_secs: u64,
+    host: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct DeliveryStats {
+    window_secs: u64,
+    host: Option<String>,
+    delivered: u64,
+    failed: u64,
+    dead_lettered: u64,
+    /// Retries dropped for a newer terminal transition; not counted in
+    /// `success_ratio`.
+    superseded: u64,
+    /// `delivered / (delivered + dead_lettered)`, i.e. the share of events that
+    /// eventually landed; `null` when nothing finished in the window.
+    success_ratio: Option<f64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PingRequest {
+    webhook_url: String,
+    /// Signs the ping with this secret instead of `ACQ_WEBHOOK_SECRET`.
+    #[serde(default)]
+    secret: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct RedeliverySummary {
+    invoice_id: InvoiceId,
+    webhook_url: String,
+    /// Transitions queued for redelivery.
+    events: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct PingResponse {
+    /// Synthetic id the ping was logged under.
+    id: InvoiceId,
+    delivered: bool,
+    status_code: Option<u16>,
+    duration_ms: u64,
+    error: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct EventsSinceQuery {
+    /// `<unix_ms>_<seq>` as returned in `next_cursor`; omit to start from the
+    /// beginning.
+    #[serde(default)]
+    cursor: Option<String>,
+    #[serde(default = "default_events_limit")]
+    limit: usize,
+}
+
+#[derive(Debug, Deserialize)]
+struct ListInvoicesQuery {
+    /// Only invoices changed strictly after this instant.
+    #[serde(default)]
+    modified_after: Option<DateTime<Utc>>,
+    /// `<updated_at>_<id>` as returned in `next_cursor`.
+    #[serde(default)]
+    cursor: Option<String>,
+    #[serde(default = "default_events_limit")]
+    limit: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct InvoicesPage {
+    /// Oldest change first.
+    invoices: Vec<Invoice>,
+    /// Pass back as `cursor` to get the next changes; unchanged when there are
+    /// none yet.
+    next_cursor: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct EventsPage {
+    events: Vec<InvoiceEvent>,
+    /// Pass back as `cursor` to get the next events; unchanged when there are
+    /// none yet.
+    next_cursor: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ReportBucket {
+    Minute,
+    #[default]
+    Hour,
+    Day,
+}
+
+impl ReportBucket {
+    fn seconds(self) -> i64 {
+        match self {
+            ReportBucket::Minute => 60,
+            ReportBucket::Hour => 3_600,
+            ReportBucket::Day => 86_400,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ReportQuery {
+    #[serde(default)]
+    bucket: ReportBucket,
+    /// Number of buckets, ending with the current one.
+    #[serde(default = "default_report_window")]
+    window: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct InvoiceReport {
+    bucket: ReportBucket,
+    window: usize,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    /// Oldest first; empty buckets are included so the series is contiguous.
+    buckets: Vec<ReportRow>,
+}
+
+#[derive(Debug, Serialize)]
+struct ReportRow {
+    start: DateTime<Utc>,
+    count: u64,
+    /// Amount per currency.
+    totals: std::collections::BTreeMap<String, u64>,
+    /// Invoice count per current status.
+    by_status: std::collections::BTreeMap<String, u64>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum DisputeOutcome {
+    Won,
+    Lost,
+}
+
+#[derive(Debug, Deserialize)]
+struct DisputeResolveQuery {
+    outcome: Option<DisputeOutcome>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum CheckoutOutcome {
+    /// The shopper left the page without paying.
+    Abandon,
+}
+
+#[derive(Debug, Deserialize)]
+struct CheckoutQuery {
+    outcome: Option<CheckoutOutcome>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum AuthenticationOutcome {
+    Success,
+    Fail,
+}
+
+#[derive(Debug, Deserialize)]
+struct AuthenticateQuery {
+    outcome: Option<AuthenticationOutcome>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ReviewDecision {
+    Approve,
+    Decline,
+}
+
+#[derive(Debug, Deserialize)]
+struct ReviewQuery {
+    decision: Option<ReviewDecision>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ConfirmQuery {
+    #[serde(default)]
+    confirm: bool,
+}
+
+/// Body of `GET /admin/snapshot` and `POST /admin/restore`.
+#[derive(Debug, Serialize, Deserialize)]
+struct Snapshot {
+    #[serde(default = "Utc::now")]
+    taken_at: DateTime<Utc>,
+    invoices: Vec<Invoice>,
+    /// Only with `include_deliveries=true`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    deliveries: Option<Vec<DeliveryRecord>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SnapshotQuery {
+    #[serde(default)]
+    include_deliveries: bool,
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum RestoreMode {
+    /// Drop the current invoices, event log and delivery log first.
+    #[default]
+    Replace,
+    /// Keep the current state; invoices whose id or `external_id` already
+    /// exists are skipped.
+    Merge,
+}
+
+#[derive(Debug, Deserialize)]
+struct RestoreQuery {
+    #[serde(default)]
+    mode: RestoreMode,
+}
+
+#[derive(Debug, Serialize)]
+struct RestoreSummary {
+    mode: RestoreMode,
+    restored: usize,
+    skipped: Vec<InvoiceId>,
+    /// Restored invoices whose pending emit or capture expiry was re-armed.
+    rescheduled: usize,
+    deliveries: usize,
+}
+
+/// Largest body `POST /admin/restore` accepts.
+const MAX_RESTORE_BYTES: usize = 64 * 1024 * 1024;
+
+#[derive(Debug, Serialize)]
+struct WebhookHostUsage {
+    host: String,
+    invoice_count: u64,
+    /// Latest delivery attempt to the host; `None` before the first one.
+    last_used_at: Option<DateTime<Utc>>,
+    urls: Vec<String>,
+}
+
+#[derive(Debug)]
+struct ApiError {
+    status: StatusCode,
+    error: &'static str,
+    message: String,
+    /// Extra top-level fields merged into the error body.
+    details: serde_json::Map<String, serde_json::Value>,
+}
+
+impl ApiError {
+    fn new(status: StatusCode, error: &'static str, message: impl Into<String>) -> Self {
+        Self { status, error, message: message.into(), details: serde_json::Map::new() }
+    }
+
+    fn with_detail(mut self, key: &str, value: impl Into<serde_json::Value>) -> Self {
+        self.details.insert(key.to_string(), value.into());
+        self
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> axum::response::Response {
+        let mut body = serde_json::json!({
+            "error": self.error,
+            "message": self.message
+        });
+        if let Some(obj) = body.as_object_mut() {
+            obj.extend(self.details.clone());
+        }
+        let mut response = (self.status, Json(body)).into_response();
+        // Kept for `problem_json`, which needs the parts rather than the body.
+        response.extensions_mut().insert(std::sync::Arc::new(self));
+        response
+    }
+}
+
+/// JSON request body. Unlike `Json`, the `Content-Type` check follows
+/// `STRICT_CONTENT_TYPE`: strict mode wants `application/json` (or a `+json`
+/// type) and answers `415` otherwise; tolerant mode parses any body as JSON.
+struct JsonBody<T>(T);
+
+#[axum::async_trait]
+impl<T: serde::de::DeserializeOwned> axum::extract::FromRequest<AppState> for JsonBody<T> {
+    type Rejection = ApiError;
+
+    async fn from_request(req: axum::extract::Request, state: &AppState) -> Result<Self, Self::Rejection> {
+        check_json_content_type(req.headers(), state)?;
+        let bytes = axum::body::Bytes::from_request(req, state)
+            .await
+            .map_err(|e| ApiError::new(e.status(), "invalid_body", e.body_text()))?;
+        parse_json_body(&bytes).map(JsonBody)
+    }
+}
+
+/// Like `JsonBody`, but an empty body yields `None` instead of a parse error.
+/// Any non-empty body goes through the same checks as `JsonBody`.
+struct OptionalJsonBody<T>(Option<T>);
+
+#[axum::async_trait]
+impl<T: serde::de::DeserializeOwned> axum::extract::FromRequest<AppState> for OptionalJsonBody<T> {
+    type Rejection = ApiError;
+
+    async fn from_request(req: axum::extract::Request, state: &AppState) -> Result<Self, Self::Rejection> {
+        let headers = req.headers().clone();
+        let bytes = axum::body::Bytes::from_request(req, state)
+            .await
+            .map_err(|e| ApiError::new(e.status(), "invalid_body", e.body_text()))?;
+        if bytes.is_empty() {
+            return Ok(OptionalJsonBody(None));
+        }
+        check_json_content_type(&headers, state)?;
+        parse_json_body(&bytes).map(|v| OptionalJsonBody(Some(v)))
+    }
+}
+
+fn check_json_content_type(headers: &HeaderMap, state: &AppState) -> Result<(), ApiError> {
+    if !state.strict_content_type {
+        return Ok(());
+    }
+    let content_type = headers
+        .get(axum::http::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("");
+    let essence = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+    if essence != "application/json" && !(essence.starts_with("application/") && essence.ends_with("+json")) {
+        let message = if content_type.is_empty() {
+            "missing Content-Type, expected application/json".to_string()
+        } else {
+            format!("unsupported Content-Type {content_type:?}, expected application/json")
+        };
+        return Err(ApiError::new(StatusCode::UNSUPPORTED_MEDIA_TYPE, "unsupported_media_type", message)
+            .with_detail("expected", "application/json"));
+    }
+    Ok(())
+}
+
+fn parse_json_body<T: serde::de::DeserializeOwned>(bytes: &[u8]) -> Result<T, ApiError> {
+    serde_json::from_slice(bytes).map_err(|e| {
+        if e.is_data() {
+            ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, "invalid_body", e.to_string())
+        } else {
+            ApiError::new(StatusCode::BAD_REQUEST, "invalid_json", e.to_string())
+        }
+    })
+}
+
+fn default_currency() -> String { "BRL".to_string() }
+fn default_emit_after_ms() -> u64 { 5_000 }
+fn default_stats_window_secs() -> u64 { 3_600 }
+fn default_events_limit() -> usize { 100 }
+fn default_seed_count() -> usize { 100 }
+fn default_report_window() -> usize { 24 }
+
+const MAX_EVENTS_LIMIT: usize = 1_000;
+const MAX_SEED_COUNT: usize = 1_000;
+const MAX_REPORT_WINDOW: usize = 1_000;
+
+/// Currencies (all known to `currency_info`) and their relative weights for
+/// `POST /_seed`, roughly matching a LatAm-heavy merchant's mix.
+const SEED_CURRENCY_WEIGHTS: &[(&str, u32)] = &[
+    ("BRL", 50),
+    ("USD", 20),
+    ("EUR", 10),
+    ("MXN", 8),
+    ("ARS", 5),
+    ("CLP", 4),
+    ("GBP", 2),
+    ("JPY", 1),
+];
+
+#[derive(Debug, Deserialize)]
+struct SeedQuery {
+    #[serde(default = "default_seed_count")]
+    count: usize,
+}
+
+fn event_cursor(at: DateTime<Utc>, seq: u64) -> String {
+    format!("{}_{}", at.timestamp_millis(), seq)
+}
+
+/// Position of an invoice in the by-last-modified order; `id` breaks ties.
+fn invoice_cursor(updated_at: DateTime<Utc>, id: &InvoiceId) -> String {
+    format!("{}_{}", updated_at.to_rfc3339_opts(chrono::SecondsFormat::Nanos, true), id)
+}
+
+fn parse_invoice_cursor(cursor: &str) -> Option<(DateTime<Utc>, String)> {
+    let (at, id) = cursor.split_once('_')?;
+    let at = DateTime::parse_from_rfc3339(at).ok()?.with_timezone(&Utc);
+    Some((at, id.to_string()))
+}
+
+/// Extracts the sequence number from a `<unix_ms>_<seq>` cursor.
+fn parse_event_cursor(cursor: &str) -> Option<u64> {
+    let (ts, seq) = cursor.split_once('_')?;
+    ts.parse::<i64>().ok()?;
+    seq.parse().ok()
+}
+
+/// Flags accepted in `FEATURES`.
+/// - `canonical_json`: send the standard webhook payload with sorted keys and
+///   no whitespace, so a consumer can re-serialize it to check the signature.
+/// - `chunked`: stream every webhook with `Transfer-Encoding: chunked`, as if
+///   each invoice set `webhook_chunked`.
+/// - `ed25519`: also sign every webhook body with Ed25519, in
+///   `X-Signature-Ed25519`.
+/// - `envelope`: wrap API responses in `{data, error, meta}`; also on with
+///   the older `API_ENVELOPE=true`.
+const KNOWN_FEATURES: &[&str] = &["canonical_json", "chunked", "ed25519", "envelope"];
+
+/// Parses a comma-separated `FEATURES` value, warning about (and dropping)
+/// names that aren't in `KNOWN_FEATURES`. Env vars that predate `FEATURES`
+/// still switch their flag on.
+fn parse_features(raw: &str) -> std::collections::BTreeSet<&'static str> {
+    let mut enabled = std::collections::BTreeSet::new();
+    if std::env::var("API_ENVELOPE").as_deref() == Ok("true") {
+        enabled.insert("envelope");
+    }
+    for name in raw.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+        match KNOWN_FEATURES.iter().find(|known| known.eq_ignore_ascii_case(name)) {
+            Some(known) => {
+                enabled.insert(*known);
+            }
+            None => warn!(feature = name, "ignoring unknown feature flag"),
+        }
+    }
+    enabled
+}
+
+// ===== State =====
+
+/// Process-lifetime counters served by `/metrics`. Atomic, so concurrent
+/// creates and deliveries are never undercounted.
+#[derive(Debug, Default)]
+struct Counters {
+    invoices_created: std::sync::atomic::AtomicU64,
+    /// Delivery attempts, indexed by `DeliveryOutcome`.
+    deliveries: [std::sync::atomic::AtomicU64; DeliveryOutcome::ALL.len()],
+    /// Outgoing webhook, verification and enrichment requests sent.
+    http_requests: std::sync::atomic::AtomicU64,
+    /// Connections the HTTP client had to open, i.e. requests that found no
+    /// idle pooled connection; against `http_requests` this is the reuse rate.
+    http_connections_opened: std::sync::atomic::AtomicU64,
+}
+
+/// Connector middleware counting each new connection into `Counters`.
+#[derive(Clone)]
+struct CountConnections<S> {
+    inner: S,
+    counters: std::sync::Arc<Counters>,
+}
+
+impl<S: tower::Service<R>, R> tower::Service<R> for CountConnections<S> {
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self, cx: &mut std::task::Context<'_>) -> std::task::Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: R) -> Self::Future {
+        self.counters.http_connections_opened.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        self.inner.call(req)
+    }
+}
+
+#[derive(Clone)]
+struct AppState {
+    invoices: std::sync::Arc<DashMap<InvoiceId, Invoice>>, 
+    idempotency: std::sync::Arc<DashMap<IdempotencyScope, IdempotencyEntry>>, 
+    idempotency_on_match: IdempotencyOnMatch,
+    /// Store `Idempotency-Key`s hashed (`IDEMPOTENCY_HASH_KEYS`).
+    idempotency_hash_keys: bool,
+    /// `external_id` → invoice, for the uniqueness check on create.
+    external_ids: std::sync::Arc<DashMap<String, InvoiceId>>,
+    /// Limits on `metadata` shape (`MAX_METADATA_KEYS`, `MAX_METADATA_DEPTH`,
+    /// `REQUIRED_METADATA_KEYS`).
+    metadata_limits: MetadataLimits,
+    /// Webhooks whose serialized `metadata` is larger than this carry
+    /// `metadata_url` instead (`METADATA_INLINE_MAX_BYTES`).
+    metadata_inline_max_bytes: Option<usize>,
+    /// Collapse identical creates this close together (`DEDUP_BY_CONTENT_WINDOW_MS`).
+    dedup_window: Option<chrono::Duration>,
+    /// Content fingerprint → latest invoice created with it, and when.
+    content_fingerprints: std::sync::Arc<DashMap<String, (InvoiceId, DateTime<Utc>)>>,
+    id_strategy: IdStrategy,
+    /// Enables test-only request options and endpoints (`TEST_MODE`).
+    test_mode: bool,
+    /// `GET /invoices/:id` acts as if an invoice younger than this did not
+    /// exist yet (`GET_LAG_MS`, `TEST_MODE` only).
+    get_lag: chrono::Duration,
+    /// What that lag answers (`GET_LAG_STATUS`: `404` or `425`).
+    get_lag_status: StatusCode,
+    /// Bearer token for diagnostic endpoints (`ADMIN_TOKEN`); they stay closed
+    /// while it is unset.
+    admin_token: Option<String>,
+    client: Client,
+    /// Named webhook targets invoices can pick with `consumer` (`CONSUMERS`).
+    consumers: std::sync::Arc<std::collections::HashMap<String, Consumer>>,
+    /// Scheduling delays are divided by this (`TIME_SCALE`, default 1.0).
+    time_scale: f64,
+    /// Longest a scheduled emit may stay pending, in real time
+    /// (`MAX_SCHEDULED_TASK_LIFETIME_SECS`, default one day).
+    max_task_lifetime: Duration,
+    /// Also send renamed payload fields under their old names
+    /// (`WEBHOOK_COMPAT_FIELDS`).
+    compat_fields: bool,
+    /// Require a JSON `Content-Type` on request bodies (`STRICT_CONTENT_TYPE`).
+    strict_content_type: bool,
+    /// Follow the first terminal webhook with `invoice.closed` (`EMIT_CLOSED_EVENT`).
+    emit_closed_event: bool,
+    /// What `invoice.viewed` reveals about the viewer (`VIEWER_MASKING`).
+    viewer_masking: ViewerMasking,
+    /// Per-tenant creation limits, keyed by `X-Tenant-Id` (`TENANT_QUOTAS`).
+    tenant_quotas: std::sync::Arc<std::collections::HashMap<String, TenantQuota>>,
+    tenant_usage: std::sync::Arc<DashMap<String, TenantUsage>>,
+    /// Used for invoices created without a `webhook_url` (`DEFAULT_WEBHOOK_URL`).
+    default_webhook_url: Option<String>,
+    /// Static `X-Acquirer-Id` fingerprint sent with every webhook
+    /// (`WEBHOOK_ACQUIRER_ID`).
+    acquirer_id: Option<String>,
+    /// `Content-Type` sent with webhooks (`WEBHOOK_CONTENT_TYPE_CHARSET`).
+    webhook_content_type: &'static str,
+    /// `Accept` sent with webhooks (`WEBHOOK_ACCEPT`, default `*/*`).
+    webhook_accept: String,
+    /// Send `Expect: 100-continue` with webhooks (`WEBHOOK_EXPECT_CONTINUE`).
+    webhook_expect_continue: bool,
+    /// Signing secrets, primary first (`ACQ_WEBHOOK_SECRETS`, or just
+    /// `ACQ_WEBHOOK_SECRET`).
+    webhook_secrets: std::sync::Arc<Vec<String>>,
+    /// Deliveries queued (grace, retry backoff) or in flight, per URL.
+    outstanding: std::sync::Arc<DashMap<String, usize>>,
+    counters: std::sync::Arc<Counters>,
+    /// Cap on `outstanding` for any one URL (`WEBHOOK_MAX_OUTSTANDING_PER_URL`).
+    max_outstanding_per_url: Option<usize>,
+    /// One delivery in flight per URL, in enqueue order
+    /// (`WEBHOOK_STRICT_ORDER_PER_URL`).
+    strict_order_per_url: bool,
+    /// Per-URL turn, taken for a whole delivery in strict-order mode. Tokio's
+    /// mutex is fair, so waiters get it in the order they asked.
+    url_turns: std::sync::Arc<DashMap<String, std::sync::Arc<tokio::sync::Mutex<()>>>>,
+    /// Deliveries waiting for their URL's turn, per URL.
+    url_turn_waiting: std::sync::Arc<DashMap<String, usize>>,
+    /// Secret per consumer host (`host` or `host:port`, lowercase), used
+    /// instead of `webhook_secrets` (`WEBHOOK_SECRETS_BY_HOST`).
+    secrets_by_host: std::sync::Arc<std::collections::HashMap<String, String>>,
+    signature_rotation: SignatureRotation,
+    signature_scheme: SignatureScheme,
+    /// Send `X-Timestamp` and sign `<timestamp>.<body>` (`WEBHOOK_SIGN_TIMESTAMP`).
+    sign_timestamp: bool,
+    /// Send an RFC 9530 `Content-Digest` of the body (`WEBHOOK_INCLUDE_DIGEST`).
+    include_digest: bool,
+    /// Also carry the payload as an HS256 JWT (`WEBHOOK_JWT`).
+    jwt: Option<JwtPlacement>,
+    event_id_strategy: EventIdStrategy,
+    rotation_counter: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+    /// Source of per-invoice seeds; seeded from `SIMULATION_SEED` when set so a
+    /// whole run is reproducible.
+    sim_rng: std::sync::Arc<std::sync::Mutex<StdRng>>,
+    /// Webhook URLs that already passed the verification handshake.
+    verified_urls: std::sync::Arc<DashMap<String, DateTime<Utc>>>,
+    verify: VerifyConfig,
+    enrich: Option<EnrichConfig>,
+    /// File behind `chaos` (`CHAOS_PROFILE`), re-read on SIGHUP.
+    chaos_path: Option<String>,
+    chaos: std::sync::Arc<std::sync::RwLock<ChaosProfile>>,
+    /// Drives the chaos draws, apart from `sim_rng` so faults don't shift the
+    /// simulation's seeds.
+    chaos_rng: std::sync::Arc<std::sync::Mutex<StdRng>>,
+    /// Invoices with a `risk_score` above this go to `Review`
+    /// (`RISK_REVIEW_THRESHOLD`).
+    risk_review_threshold: Option<u8>,
+    /// Locale used for `amount_formatted` (`LOCALE`, default `pt-BR`).
+    locale: String,
+    /// Offset for the payload's local timestamps (`TIMEZONE`, default UTC).
+    timezone: chrono::FixedOffset,
+    /// Every delivery attempt, in the order they happened.
+    deliveries: std::sync::Arc<std::sync::RwLock<Vec<DeliveryRecord>>>,
+    /// Last `RECENT_DELIVERIES_BUFFER` attempts with headers and a payload
+    /// preview, oldest at the front.
+    recent: std::sync::Arc<std::sync::Mutex<std::collections::VecDeque<RecentDelivery>>>,
+    recent_capacity: usize,
+    /// Channels to `grpc://` webhook URLs, one per authority, reused across
+    /// deliveries.
+    #[cfg(feature = "grpc")]
+    grpc_channels: std::sync::Arc<DashMap<String, tonic::transport::Channel>>,
+    /// Method called on `grpc://` webhook URLs (`WEBHOOK_GRPC_METHOD`).
+    #[cfg(feature = "grpc")]
+    grpc_method: String,
+    /// Dead-lettered webhooks that can still be redriven, oldest first, at
+    /// most `MAX_DEAD_LETTERS`.
+    dead_letters: std::sync::Arc<std::sync::Mutex<std::collections::VecDeque<DeadLetter>>>,
+    /// Progress of `POST /admin/deadletters/redrive-all` runs, by id.
+    redrives: std::sync::Arc<DashMap<String, RedriveJob>>,
+    retry: RetryConfig,
+    /// Every invoice transition, in `seq` order (`seq` = index + 1).
+    events: std::sync::Arc<std::sync::RwLock<Vec<InvoiceEvent>>>,
+    /// Last run of `POST /admin/expire-pending`, for its rate limit.
+    last_mass_expiry: std::sync::Arc<std::sync::Mutex<Option<std::time::Instant>>>,
+    mass_expiry_min_interval: Duration,
+    /// Where this instance can reach itself, for the `/_sink` webhook URLs of
+    /// seeded invoices (`SELF_BASE_URL`).
+    self_base_url: String,
+    /// Public base for `checkout_url` and absolute `Location` headers
+    /// (`CHECKOUT_BASE_URL`, no trailing slash).
+    checkout_base_url: Option<String>,
+    /// Experimental behaviors switched on via `FEATURES`.
+    features: std::sync::Arc<std::collections::BTreeSet<&'static str>>,
+    /// Key for `X-Signature-Ed25519`, with the `ed25519` feature
+    /// (`WEBHOOK_ED25519_SEED`, or a fresh one per process).
+    ed25519_key: Option<std::sync::Arc<ring::signature::Ed25519KeyPair>>,
+    /// Server-wide replacement for the standard webhook body
+    /// (`WEBHOOK_BODY_TEMPLATE`).
+    body_template: Option<std::sync::Arc<BodyTemplate>>,
+    /// Settlement batching; off unless `SETTLEMENT_BATCH_INTERVAL_SECS` or
+    /// `SETTLEMENT_BATCH_CLOSE_AT` is set.
+    batching: Option<BatchSchedule>,
+    batches: std::sync::Arc<DashMap<String, SettlementBatch>>,
+    /// Open batch id per webhook URL.
+    open_batches: std::sync::Arc<DashMap<String, String>>,
+    /// Deliveries due before this are held until then
+    /// (`WEBHOOK_STARTUP_GRACE_MS`, counted from process start).
+    grace_until: Option<tokio::time::Instant>,
+    /// `/_sink` attempts seen per `X-Event-Id`, for `sink_attempt_profile`.
+    sink_attempts: std::sync::Arc<DashMap<String, u32>>,
+    /// JSONL file every outbound webhook is appended to (`WEBHOOK_RECORD`).
+    recorder: Option<std::sync::Arc<std::sync::Mutex<std::fs::File>>>,
+}
+
+/// Batch windows are `interval` long and aligned so that one of them ends at
+/// `close_offset` past midnight UTC.
+#[derive(Clone, Copy)]
+struct BatchSchedule {
+    interval: Duration,
+    close_offset: Duration,
+}
+
+impl BatchSchedule {
+    fn from_env() -> Option<Self> {
+        let interval = std::env::var("SETTLEMENT_BATCH_INTERVAL_SECS").ok().and_then(|v| v.parse::<u64>().ok()).filter(|&s| s > 0);
+        let close_at = std::env::var("SETTLEMENT_BATCH_CLOSE_AT").ok().map(|v| {
+            chrono::NaiveTime::parse_from_str(&v, "%H:%M").expect("SETTLEMENT_BATCH_CLOSE_AT must be HH:MM (UTC)")
+        });
+        if interval.is_none() && close_at.is_none() {
+            return None;
+        }
+        let close_offset = close_at.map_or(0, |t| chrono::Timelike::num_seconds_from_midnight(&t) as u64);
+        Some(BatchSchedule {
+            interval: Duration::from_secs(interval.unwrap_or(86_400)),
+            close_offset: Duration::from_secs(close_offset),
+        })
+    }
+
+    /// First window end strictly after `now`.
+    fn next_close(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        let interval = self.interval.as_secs() as i64;
+        let offset = self.close_offset.as_secs() as i64 % interval;
+        let elapsed = (now.timestamp() - offset).rem_euclid(interval);
+        let next = now.timestamp() - elapsed + interval;
+        DateTime::from_timestamp(next, 0).unwrap_or(now)
+    }
+}
+
+#[derive(Clone)]
+struct RetryConfig {
+    /// Extra attempts after the first one (`WEBHOOK_MAX_RETRIES`).
+    max_retries: u32,
+    /// Upper bound for per-invoice `max_retries` (`WEBHOOK_MAX_RETRIES_CEILING`).
+    max_retries_ceiling: u32,
+    /// Delay before the first retry, doubled on each subsequent one.
+    base_delay: Duration,
+    /// Per-attempt request timeout.
+    timeout: Duration,
+    /// A 2xx whose body matches this still counts as a failure
+    /// (`WEBHOOK_RETRY_ON_BODY_REGEX`).
+    retry_on_body: Option<regex::Regex>,
+    /// Per-status overrides, first match wins (`WEBHOOK_RETRY_RULES`).
+    rules: std::sync::Arc<Vec<RetryRule>>,
+}
+
+impl RetryConfig {
+    fn backoff(&self, retry: u32) -> Duration {
+        self.base_delay.saturating_mul(2u32.saturating_pow(retry - 1))
+    }
+
+    fn rule_for(&self, status: Option<u16>) -> Option<&RetryRule> {
+        self.rules.iter().find(|rule| rule.codes.matches(status))
+    }
+}
+
+/// Scenario file with the chaos knobs (`CHAOS_PROFILE`), reloaded on SIGHUP.
+/// Everything defaults to off, so an empty file injects nothing.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ChaosProfile {
+    /// Seeds the chaos RNG so a campaign's faults repeat run to run.
+    #[serde(default)]
+    seed: Option<u64>,
+    #[serde(default)]
+    api: ApiChaos,
+    #[serde(default)]
+    webhooks: WebhookChaos,
+    #[serde(default)]
+    retry: RetryOverrides,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ApiChaos {
+    /// Extra latency drawn uniformly for every API request.
+    #[serde(default)]
+    latency_ms: Option<LatencyRange>,
+    /// Share of creates answered `503 chaos_create_failure`.
+    #[serde(default)]
+    create_failure_rate: f64,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LatencyRange {
+    min: u64,
+    max: u64,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct WebhookChaos {
+    /// Share of attempts silently not sent yet recorded as delivered.
+    #[serde(default)]
+    drop_rate: f64,
+    /// Share of attempts not sent and failed as if the connection broke.
+    #[serde(default)]
+    failure_rate: f64,
+}
+
+/// Replace the `WEBHOOK_RETRY_*` settings while the profile is loaded.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RetryOverrides {
+    #[serde(default)]
+    max_retries: Option<u32>,
+    #[serde(default)]
+    base_delay_ms: Option<u64>,
+    #[serde(default)]
+    timeout_ms: Option<u64>,
+}
+
+/// Longest latency a chaos profile may add to a request.
+const MAX_CHAOS_LATENCY_MS: u64 = 60_000;
+
+impl ChaosProfile {
+    fn load(path: &str) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        let profile: ChaosProfile = serde_yaml::from_str(&text).map_err(|e| format!("{}: {}", path, e))?;
+        profile.validate().map_err(|e| format!("{}: {}", path, e))?;
+        Ok(profile)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let rates = [
+            ("api.create_failure_rate", self.api.create_failure_rate),
+            ("webhooks.drop_rate", self.webhooks.drop_rate),
+            ("webhooks.failure_rate", self.webhooks.failure_rate),
+        ];
+        for (name, rate) in rates {
+            if !(0.0..=1.0).contains(&rate) {
+                return Err(format!("{} must be between 0 and 1, got {}", name, rate));
+            }
+        }
+        if self.webhooks.drop_rate + self.webhooks.failure_rate > 1.0 {
+            return Err("webhooks.drop_rate + webhooks.failure_rate must not exceed 1".into());
+        }
+        if let Some(LatencyRange { min, max }) = self.api.latency_ms {
+            if min > max {
+                return Err(format!("api.latency_ms.min ({}) is greater than max ({})", min, max));
+            }
+            if max > MAX_CHAOS_LATENCY_MS {
+                return Err(format!("api.latency_ms.max must be at most {}, got {}", MAX_CHAOS_LATENCY_MS, max));
+            }
+        }
+        if self.retry.base_delay_ms == Some(0) {
+            return Err("retry.base_delay_ms must be greater than 0".into());
+        }
+        if self.retry.timeout_ms == Some(0) {
+            return Err("retry.timeout_ms must be greater than 0".into());
+        }
+        Ok(())
+    }
+}
+
+/// What the chaos profile did to a delivery attempt.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ChaosFault {
+    Dropped,
+    Failed,
+}
+
+/// Longest `Retry-After` a `respect_retry_after` rule will wait.
+const MAX_RETRY_AFTER_SECS: u64 = 600;
+
+/// Which failed attempts a `RetryRule` covers.
+#[derive(Debug, Clone, Copy)]
+enum CodeMatch {
+    /// No response at all (connection error, timeout).
+    Network,
+    /// Inclusive status range.
+    Range(u16, u16),
+}
+
+impl CodeMatch {
+    /// Parses `"network"`, `"429"`, `"500-503"` or `"4xx"`.
+    fn parse(spec: &str) -> Option<Self> {
+        let spec = spec.trim();
+        if spec == "network" {
+            return Some(CodeMatch::Network);
+        }
+        if let Some(class) = spec.strip_suffix("xx") {
+            let class: u16 = class.parse().ok().filter(|c| (1..=5).contains(c))?;
+            return Some(CodeMatch::Range(class * 100, class * 100 + 99));
+        }
+        let (lo, hi) = spec.split_once('-').unwrap_or((spec, spec));
+        let lo: u16 = lo.trim().parse().ok()?;
+        let hi: u16 = hi.trim().parse().ok()?;
+        ((100..=599).contains(&lo) && (lo..=599).contains(&hi)).then_some(CodeMatch::Range(lo, hi))
+    }
+
+    fn matches(self, status: Option<u16>) -> bool {
+        match (self, status) {
+            (CodeMatch::Network, None) => true,
+            (CodeMatch::Range(lo, hi), Some(code)) => (lo..=hi).contains(&code),
+            _ => false,
+        }
+    }
+}
+
+/// Retry policy for failed attempts whose status falls in `codes`.
+#[derive(Debug, Clone)]
+struct RetryRule {
+    /// As written in the config; recorded on the attempts it decided.
+    name: String,
+    codes: CodeMatch,
+    /// Replaces `WEBHOOK_MAX_RETRIES`; still capped by the ceiling and by a
+    /// per-invoice `max_retries`.
+    max_retries: u32,
+    /// Wait what the consumer's `Retry-After` (seconds) asks instead of the
+    /// usual backoff, when it sends one.
+    respect_retry_after: bool,
+}
+
+/// Parses `WEBHOOK_RETRY_RULES`, a JSON array of
+/// `{"codes": "500-503", "max_retries": 5, "respect_retry_after": false}`.
+fn parse_retry_rules(raw: &str) -> Vec<RetryRule> {
+    #[derive(Deserialize)]
+    struct Spec {
+        codes: String,
+        max_retries: u32,
+        #[serde(default)]
+        respect_retry_after: bool,
+    }
+    let specs: Vec<Spec> = serde_json::from_str(raw)
+        .expect("WEBHOOK_RETRY_RULES must be a JSON array of {codes, max_retries, respect_retry_after}");
+    specs
+        .into_iter()
+        .map(|spec| RetryRule {
+            codes: CodeMatch::parse(&spec.codes).unwrap_or_else(|| {
+                panic!("WEBHOOK_RETRY_RULES: invalid codes {:?} (use \"network\", \"429\", \"500-503\" or \"4xx\")", spec.codes)
+            }),
+            name: spec.codes,
+            max_retries: spec.max_retries,
+            respect_retry_after: spec.respect_retry_after,
+        })
+        .collect()
+}
+
+#[derive(Clone)]
+struct VerifyConfig {
+    method: VerifyMethod,
+    expect: VerifyExpect,
+    timeout: Duration,
+}
+
+/// Pre-create call to an external enrichment service (`ENRICH_URL`).
+#[derive(Clone)]
+struct EnrichConfig {
+    url: String,
+    timeout: Duration,
+    /// Reject the create when the call fails (`ENRICH_ON_ERROR=fail`) instead
+    /// of creating the invoice unenriched.
+    fail_closed: bool,
+}
+
+#[derive(Clone, Copy)]
+enum VerifyMethod {
+    /// `GET <url>?challenge=<token>`
+    Get,
+    /// Signed `POST` with `{"event":"webhook.verification","challenge":<token>}`.
+    Post,
+}
+
+#[derive(Clone, Copy)]
+enum VerifyExpect {
+    /// Response body must be exactly the token (surrounding whitespace ignored).
+    Body,
+    /// Response body must be JSON with `"challenge": <token>`.
+    Json,
+}
+
+/// How webhook `event_id`s are derived (`EVENT_ID_STRATEGY`). Both are a pure
+/// function of (invoice, transition seq, event), so retries and redeliveries of
+/// the same logical event share the id.
+#[derive(Clone, Copy)]
+enum EventIdStrategy {
+    /// `evt_` + first 24 hex chars of SHA-256 over the triple.
+    Hash,
+    /// `<invoice_id>:<seq>:<event>`, easy to eyeball in logs.
+    Readable,
+}
+
+/// How much of the viewer `invoice.viewed` reports (`VIEWER_MASKING`).
+#[derive(Clone, Copy)]
+enum ViewerMasking {
+    /// IPv4 with the last octet zeroed, IPv6 cut to its /48; user agent as sent.
+    Partial,
+    /// IP and user agent both `[redacted]`.
+    Full,
+    /// Everything as seen.
+    Off,
+}
+
+impl ViewerMasking {
+    fn ip(self, ip: std::net::IpAddr) -> String {
+        match (self, ip) {
+            (ViewerMasking::Off, ip) => ip.to_string(),
+            (ViewerMasking::Full, _) => "[redacted]".to_string(),
+            (ViewerMasking::Partial, std::net::IpAddr::V4(v4)) => {
+                let [a, b, c, _] = v4.octets();
+                std::net::Ipv4Addr::new(a, b, c, 0).to_string()
+            }
+            (ViewerMasking::Partial, std::net::IpAddr::V6(v6)) => {
+                let [a, b, c, ..] = v6.segments();
+                std::net::Ipv6Addr::new(a, b, c, 0, 0, 0, 0, 0).to_string()
+            }
+        }
+    }
+
+    fn user_agent(self, user_agent: String) -> String {
+        match self {
+            ViewerMasking::Full => "[redacted]".to_string(),
+            ViewerMasking::Partial | ViewerMasking::Off => user_agent,
+        }
+    }
+}
+
+/// How multiple signing secrets are used (`SIGNATURE_ROTATION`).
+#[derive(Clone, Copy)]
+enum SignatureRotation {
+    /// Every delivery carries one signature per secret, comma-separated.
+    Dual,
+    /// Each delivery is signed with a single secret, taking turns.
+    RoundRobin,
+}
+
+/// Layout of `X-Signature` (`WEBHOOK_SIGNATURE_SCHEME`).
+#[derive(Clone, Copy, PartialEq)]
+enum SignatureScheme {
+    /// Bare hex HMAC-SHA256, of `<X-Timestamp>.<body>` with
+    /// `WEBHOOK_SIGN_TIMESTAMP`, of the body otherwise.
+    Legacy,
+    /// `scheme=v2,t=<unix>,v1=<hex>[,v1=<hex>...]`, each `v1` an HMAC-SHA256
+    /// of `v2.<t>.<body>`. Signing the scheme id means a signature can't be
+    /// replayed under a different scheme.
+    V2,
+}
+
+/// Where the webhook JWT goes (`WEBHOOK_JWT_PLACEMENT`).
+#[derive(Clone, Copy, PartialEq)]
+enum JwtPlacement {
+    /// `Authorization: Bearer <jwt>`, next to the usual JSON body.
+    Header,
+    /// The JWT is the whole body, sent as `application/jwt`.
+    Body,
+}
+
+#[derive(Clone)]
+struct IdempotencyEntry {
+    invoice_id: InvoiceId,
+    /// SHA-256 of the re-serialized request, so formatting differences in the
+    /// raw JSON do not count as a different body.
+    body_hash: String,
+    /// The exact response first sent for this key; replays return it
+    /// unchanged even if the invoice has moved on since.
+    status: StatusCode,
+    response: axum::body::Bytes,
+}
+
+/// `(operation, Idempotency-Key)`: the same key may be reused across
+/// operations, e.g. `create` and `capture:<invoice id>`.
+type IdempotencyScope = (String, String);
+
+fn idempotency_key(headers: &HeaderMap) -> Option<String> {
+    headers.get("Idempotency-Key").and_then(|v| v.to_str().ok()).map(|s| s.to_string())
+}
+
+/// A pre-serialized JSON response, so what is stored for idempotent replays is
+/// byte-for-byte what the client got.
+fn json_bytes(status: StatusCode, mut headers: HeaderMap, body: axum::body::Bytes) -> (StatusCode, HeaderMap, axum::body::Bytes) {
+    headers.insert(axum::http::header::CONTENT_TYPE, axum::http::HeaderValue::from_static("application/json"));
+    (status, headers, body)
+}
+
+/// What to do when an `Idempotency-Key` is replayed (`IDEMPOTENCY_ON_MATCH`).
+#[derive(Clone, Copy)]
+enum IdempotencyOnMatch {
+    /// Return the original invoice, whatever the new body says.
+    ReturnOriginal,
+    /// Return the original only if the body matches; `422` otherwise.
+    ErrorIfDifferent,
+    /// Return the original, flagging a differing body with
+    /// `X-Idempotency-Body-Mismatch: true`.
+    ReturnOriginalWithWarning,
+}
+
+impl AppState {
+    /// Builds the state from environment variables (after `.env` is loaded).
+    fn from_env() -> Self {
+        let counters = std::sync::Arc::new(Counters::default());
+        let features = parse_features(&std::env::var("FEATURES").unwrap_or_default());
+        AppState {
+            invoices: std::sync::Arc::new(DashMap::new()),
+            idempotency: std::sync::Arc::new(DashMap::new()),
+            idempotency_on_match: match std::env::var("IDEMPOTENCY_ON_MATCH").as_deref() {
+                Ok("error_if_different") => IdempotencyOnMatch::ErrorIfDifferent,
+                Ok("return_original_with_warning") => IdempotencyOnMatch::ReturnOriginalWithWarning,
+                _ => IdempotencyOnMatch::ReturnOriginal,
+            },
+            idempotency_hash_keys: std::env::var("IDEMPOTENCY_HASH_KEYS").as_deref() == Ok("true"),
+            external_ids: std::sync::Arc::new(DashMap::new()),
+            metadata_limits: MetadataLimits {
+                max_keys: std::env::var("MAX_METADATA_KEYS").ok().and_then(|v| v.parse().ok()),
+                max_depth: std::env::var("MAX_METADATA_DEPTH").ok().and_then(|v| v.parse().ok()),
+                required_keys: std::env::var("REQUIRED_METADATA_KEYS")
+                    .map(|v| v.split(',').map(str::trim).filter(|key| !key.is_empty()).map(String::from).collect())
+                    .unwrap_or_default(),
+            },
+            metadata_inline_max_bytes: std::env::var("METADATA_INLINE_MAX_BYTES").ok().and_then(|v| v.parse().ok()),
+            dedup_window: std::env::var("DEDUP_BY_CONTENT_WINDOW_MS")
+                .ok()
+                .and_then(|v| v.parse::<i64>().ok())
+                .filter(|ms| *ms > 0)
+                .map(chrono::Duration::milliseconds),
+            content_fingerprints: std::sync::Arc::new(DashMap::new()),
+            test_mode: std::env::var("TEST_MODE").as_deref() == Ok("true"),
+            get_lag: chrono::Duration::milliseconds(
+                std::env::var("GET_LAG_MS").ok().and_then(|v| v.parse::<i64>().ok()).unwrap_or(0).max(0),
+            ),
+            get_lag_status: match std::env::var("GET_LAG_STATUS").as_deref() {
+                Ok("425") => StatusCode::TOO_EARLY,
+                _ => StatusCode::NOT_FOUND,
+            },
+            admin_token: std::env::var("ADMIN_TOKEN").ok().filter(|t| !t.is_empty()),
+            id_strategy: match std::env::var("ID_STRATEGY").as_deref() {
+                Ok("uuidv7") => IdStrategy::UuidV7,
+                Ok("nanoid") => IdStrategy::Nanoid,
+                _ => IdStrategy::UuidV4,
+            },
+            client: {
+                let user_agent = std::env::var("WEBHOOK_USER_AGENT")
+                    .ok()
+                    .filter(|ua| !ua.is_empty())
+                    .unwrap_or_else(|| concat!("tickpay/", env!("CARGO_PKG_VERSION")).to_string());
+                axum::http::HeaderValue::from_str(&user_agent).expect("WEBHOOK_USER_AGENT is not a valid header value");
+                let builder = Client::builder().user_agent(user_agent);
+                // reqwest's defaults: unlimited idle connections per host, dropped after 90s.
+                let builder = match std::env::var("WEBHOOK_POOL_MAX_IDLE_PER_HOST").ok().and_then(|v| v.parse().ok()) {
+                    Some(max) => builder.pool_max_idle_per_host(max),
+                    None => builder,
+                };
+                let builder = match std::env::var("WEBHOOK_POOL_IDLE_TIMEOUT_SECS").ok().and_then(|v| v.parse().ok()) {
+                    Some(secs) => builder.pool_idle_timeout(Duration::from_secs(secs)),
+                    None => builder,
+                };
+                let connection_counters = counters.clone();
+                let builder = builder.connector_layer(tower::layer::layer_fn(move |inner| CountConnections {
+                    inner,
+                    counters: connection_counters.clone(),
+                }));
+                // hyper writes lowercase header names on HTTP/1 by default; `canonical`
+                // sends `X-Signature`/`Content-Type` style casing instead.
+                let builder = match std::env::var("WEBHOOK_HEADER_CASING").as_deref() {
+                    Ok("canonical") => builder.http1_title_case_headers(),
+                    _ => builder,
+                };
+                // Following a redirect re-sends the signed body, so by default a 3xx
+                // is just a failed delivery.
+                let redirects = match std::env::var("WEBHOOK_FOLLOW_REDIRECTS").as_deref() {
+                    Ok("all") => reqwest::redirect::Policy::limited(10),
+                    Ok("same_host") => reqwest::redirect::Policy::custom(|attempt| {
+                        let origin = &attempt.previous()[0];
+                        if attempt.previous().len() > 10 {
+                            attempt.error("too many redirects")
+                        } else if attempt.url().host_str() == origin.host_str()
+                            && attempt.url().port_or_known_default() == origin.port_or_known_default()
+                        {
+                            attempt.follow()
+                        } else {
+                            let msg = format!("refused redirect to a different host: {}", attempt.url());
+                            attempt.error(msg)
+                        }
+                    }),
+                    _ => reqwest::redirect::Policy::none(),
+                };
+                builder.redirect(redirects).build().expect("http client")
+            },
+            consumers: std::sync::Arc::new(
+                std::env::var("CONSUMERS").ok().filter(|v| !v.trim().is_empty()).map(|v| parse_consumers(&v)).unwrap_or_default(),
+            ),
+            time_scale: std::env::var("TIME_SCALE")
+                .ok()
+                .and_then(|v| v.parse::<f64>().ok())
+                .filter(|scale| scale.is_finite() && *scale > 0.0)
+                .map_or(1.0, |scale| scale.clamp(MIN_TIME_SCALE, MAX_TIME_SCALE)),
+            max_task_lifetime: Duration::from_secs(
+                std::env::var("MAX_SCHEDULED_TASK_LIFETIME_SECS")
+                    .ok()
+                    .and_then(|v| v.parse().ok())
+                    .filter(|&secs| secs > 0)
+                    .unwrap_or(86_400),
+            ),
+            compat_fields: std::env::var("WEBHOOK_COMPAT_FIELDS").as_deref() == Ok("true"),
+            strict_content_type: std::env::var("STRICT_CONTENT_TYPE").as_deref() != Ok("false"),
+            emit_closed_event: std::env::var("EMIT_CLOSED_EVENT").as_deref() == Ok("true"),
+            viewer_masking: match std::env::var("VIEWER_MASKING").as_deref() {
+                Ok("full") => ViewerMasking::Full,
+                Ok("off") => ViewerMasking::Off,
+                _ => ViewerMasking::Partial,
+            },
+            tenant_quotas: std::sync::Arc::new(
+                std::env::var("TENANT_QUOTAS")
+                    .ok()
+                    .filter(|v| !v.trim().is_empty())
+                    .map(|v| {
+                        serde_json::from_str(&v)
+                            .expect("TENANT_QUOTAS must be a JSON object of tenant -> {rate_per_min, daily_quota}")
+                    })
+                    .unwrap_or_default(),
+            ),
+            tenant_usage: std::sync::Arc::new(DashMap::new()),
+            default_webhook_url: std::env::var("DEFAULT_WEBHOOK_URL").ok().map(|url| url.trim().to_string()).filter(|url| !url.is_empty()),
+            acquirer_id: std::env::var("WEBHOOK_ACQUIRER_ID").ok().filter(|id| !id.is_empty()).inspect(|id| {
+                axum::http::HeaderValue::from_str(id).expect("WEBHOOK_ACQUIRER_ID is not a valid header value");
+            }),
+            webhook_content_type: if std::env::var("WEBHOOK_CONTENT_TYPE_CHARSET").as_deref() == Ok("true") {
+                "application/json; charset=utf-8"
+            } else {
+                "application/json"
+            },
+            webhook_accept: std::env::var("WEBHOOK_ACCEPT")
+                .ok()
+                .filter(|v| !v.is_empty())
+                .inspect(|v| {
+                    axum::http::HeaderValue::from_str(v).expect("WEBHOOK_ACCEPT is not a valid header value");
+                })
+                .unwrap_or_else(|| "*/*".to_string()),
+            webhook_expect_continue: std::env::var("WEBHOOK_EXPECT_CONTINUE").as_deref() == Ok("true"),
+            webhook_secrets: std::sync::Arc::new({
+                let secrets: Vec<String> = std::env::var("ACQ_WEBHOOK_SECRETS")
+                    .unwrap_or_default()
+                    .split(',')
+                    .map(|s| s.trim().to_string())
+                    .filter(|s| !s.is_empty())
+                    .collect();
+                if secrets.is_empty() {
+                    vec![std::env::var("ACQ_WEBHOOK_SECRET").unwrap_or_else(|_| "dev_secret".into())]
+                } else {
+                    secrets
+                }
+            }),
+            outstanding: std::sync::Arc::new(DashMap::new()),
+            counters: counters.clone(),
+            max_outstanding_per_url: std::env::var("WEBHOOK_MAX_OUTSTANDING_PER_URL")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .filter(|&n: &usize| n > 0),
+            strict_order_per_url: std::env::var("WEBHOOK_STRICT_ORDER_PER_URL").as_deref() == Ok("true"),
+            url_turns: std::sync::Arc::new(DashMap::new()),
+            url_turn_waiting: std::sync::Arc::new(DashMap::new()),
+            secrets_by_host: std::sync::Arc::new(
+                std::env::var("WEBHOOK_SECRETS_BY_HOST")
+                    .ok()
+                    .filter(|v| !v.trim().is_empty())
+                    .map(|v| {
+                        let map: std::collections::HashMap<String, String> = serde_json::from_str(&v)
+                            .expect("WEBHOOK_SECRETS_BY_HOST must be a JSON object of host -> secret");
+                        // Only the host is named: the secret itself must never reach the logs.
+                        if let Some((host, _)) = map.iter().find(|(_, secret)| secret.is_empty()) {
+                            panic!("WEBHOOK_SECRETS_BY_HOST: empty secret for {}", host);
+                        }
+                        map.into_iter().map(|(host, secret)| (host.to_ascii_lowercase(), secret)).collect()
+                    })
+                    .unwrap_or_default(),
+            ),
+            signature_rotation: match std::env::var("SIGNATURE_ROTATION").as_deref() {
+                Ok("roundrobin") => SignatureRotation::RoundRobin,
+                _ => SignatureRotation::Dual,
+            },
+            signature_scheme: match std::env::var("WEBHOOK_SIGNATURE_SCHEME").as_deref() {
+                Ok("v2") => SignatureScheme::V2,
+                Ok("legacy") | Err(_) => SignatureScheme::Legacy,
+                Ok(other) => panic!("WEBHOOK_SIGNATURE_SCHEME must be legacy or v2, got {}", other),
+            },
+            sign_timestamp: std::env::var("WEBHOOK_SIGN_TIMESTAMP").as_deref() == Ok("true"),
+            include_digest: std::env::var("WEBHOOK_INCLUDE_DIGEST").as_deref() == Ok("true"),
+            jwt: (std::env::var("WEBHOOK_JWT").as_deref() == Ok("true")).then(|| {
+                match std::env::var("WEBHOOK_JWT_PLACEMENT").as_deref() {
+                    Ok("body") => JwtPlacement::Body,
+                    _ => JwtPlacement::Header,
+                }
+            }),
+            event_id_strategy: match std::env::var("EVENT_ID_STRATEGY").as_deref() {
+                Ok("readable") => EventIdStrategy::Readable,
+                _ => EventIdStrategy::Hash,
+            },
+            rotation_counter: std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0)),
+            sim_rng: std::sync::Arc::new(std::sync::Mutex::new(
+                match std::env::var("SIMULATION_SEED").ok().and_then(|v| v.parse().ok()) {
+                    Some(seed) => StdRng::seed_from_u64(seed),
+                    None => StdRng::from_os_rng(),
+                },
+            )),
+            verified_urls: std::sync::Arc::new(DashMap::new()),
+            verify: VerifyConfig {
+                method: match std::env::var("WEBHOOK_VERIFY_METHOD").as_deref() {
+                    Ok("get") => VerifyMethod::Get,
+                    _ => VerifyMethod::Post,
+                },
+                expect: match std::env::var("WEBHOOK_VERIFY_EXPECT").as_deref() {
+                    Ok("body") => VerifyExpect::Body,
+                    _ => VerifyExpect::Json,
+                },
+                timeout: Duration::from_millis(
+                    std::env::var("WEBHOOK_VERIFY_TIMEOUT_MS").ok().and_then(|v| v.parse().ok()).unwrap_or(5_000),
+                ),
+            },
+            enrich: std::env::var("ENRICH_URL").ok().filter(|url| !url.trim().is_empty()).map(|url| EnrichConfig {
+                url,
+                timeout: Duration::from_millis(
+                    std::env::var("ENRICH_TIMEOUT_MS").ok().and_then(|v| v.parse().ok()).unwrap_or(1_000),
+                ),
+                fail_closed: std::env::var("ENRICH_ON_ERROR").as_deref() == Ok("fail"),
+            }),
+            chaos_path: std::env::var("CHAOS_PROFILE").ok().filter(|path| !path.trim().is_empty()),
+            chaos: std::sync::Arc::new(std::sync::RwLock::new(ChaosProfile::default())),
+            chaos_rng: std::sync::Arc::new(std::sync::Mutex::new(StdRng::from_os_rng())),
+            risk_review_threshold: std::env::var("RISK_REVIEW_THRESHOLD").ok().and_then(|v| v.parse().ok()),
+            locale: std::env::var("LOCALE")
+                .ok()
+                .filter(|l| locale_format(l).is_some())
+                .unwrap_or_else(|| "pt-BR".into()),
+            timezone: std::env::var("TIMEZONE")
+                .ok()
+                .and_then(|v| parse_utc_offset(&v))
+                .unwrap_or_else(|| chrono::FixedOffset::east_opt(0).unwrap()),
+            deliveries: std::sync::Arc::new(std::sync::RwLock::new(Vec::new())),
+            recent: std::sync::Arc::new(std::sync::Mutex::new(std::collections::VecDeque::new())),
+            dead_letters: std::sync::Arc::new(std::sync::Mutex::new(std::collections::VecDeque::new())),
+            redrives: std::sync::Arc::new(DashMap::new()),
+            recent_capacity: std::env::var("RECENT_DELIVERIES_BUFFER").ok().and_then(|v| v.parse().ok()).unwrap_or(1_000),
+            #[cfg(feature = "grpc")]
+            grpc_channels: std::sync::Arc::new(DashMap::new()),
+            #[cfg(feature = "grpc")]
+            grpc_method: {
+                let method = std::env::var("WEBHOOK_GRPC_METHOD").unwrap_or_else(|_| grpc::DELIVER_METHOD.to_string());
+                if let Err(e) = method.parse::<tonic::codegen::http::uri::PathAndQuery>() {
+                    panic!("WEBHOOK_GRPC_METHOD is invalid: {}", e);
+                }
+                method
+            },
+            events: std::sync::Arc::new(std::sync::RwLock::new(Vec::new())),
+            retry: RetryConfig {
+                max_retries: std::env::var("WEBHOOK_MAX_RETRIES").ok().and_then(|v| v.parse().ok()).unwrap_or(0),
+                max_retries_ceiling: std::env::var("WEBHOOK_MAX_RETRIES_CEILING")
+                    .ok()
+                    .and_then(|v| v.parse().ok())
+                    .unwrap_or(20),
+                base_delay: Duration::from_millis(
+                    std::env::var("WEBHOOK_RETRY_BASE_MS").ok().and_then(|v| v.parse().ok()).unwrap_or(1_000),
+                ),
+                timeout: Duration::from_millis(
+                    std::env::var("WEBHOOK_TIMEOUT_MS").ok().and_then(|v| v.parse().ok()).unwrap_or(10_000),
+                ),
+                retry_on_body: std::env::var("WEBHOOK_RETRY_ON_BODY_REGEX")
+                    .ok()
+                    .filter(|re| !re.is_empty())
+                    .map(|re| regex::Regex::new(&re).expect("WEBHOOK_RETRY_ON_BODY_REGEX is not a valid regex")),
+                rules: std::sync::Arc::new(
+                    std::env::var("WEBHOOK_RETRY_RULES")
+                        .ok()
+                        .filter(|v| !v.trim().is_empty())
+                        .map(|v| parse_retry_rules(&v))
+                        .unwrap_or_default(),
+                ),
+            },
+            last_mass_expiry: std::sync::Arc::new(std::sync::Mutex::new(None)),
+            mass_expiry_min_interval: Duration::from_secs(
+                std::env::var("ADMIN_EXPIRE_MIN_INTERVAL_SECS").ok().and_then(|v| v.parse().ok()).unwrap_or(10),
+            ),
+            self_base_url: std::env::var("SELF_BASE_URL").unwrap_or_else(|_| {
+                let port: u16 = std::env::var("PORT").ok().and_then(|v| v.parse().ok()).unwrap_or(8080);
+                format!("http://127.0.0.1:{}", port)
+            }),
+            checkout_base_url: std::env::var("CHECKOUT_BASE_URL")
+                .ok()
+                .map(|url| url.trim_end_matches('/').to_string())
+                .filter(|url| !url.is_empty() && axum::http::HeaderValue::from_str(url).is_ok()),
+            ed25519_key: features.contains("ed25519").then(|| {
+                let seed = match std::env::var("WEBHOOK_ED25519_SEED") {
+                    Ok(hex_seed) => hex::decode(hex_seed.trim())
+                        .ok()
+                        .filter(|seed| seed.len() == 32)
+                        .unwrap_or_else(|| panic!("WEBHOOK_ED25519_SEED is invalid: expected 64 hex characters")),
+                    Err(_) => rand::rng().random::<[u8; 32]>().to_vec(),
+                };
+                let key = ring::signature::Ed25519KeyPair::from_seed_unchecked(&seed)
+                    .unwrap_or_else(|e| panic!("WEBHOOK_ED25519_SEED is invalid: {}", e));
+                std::sync::Arc::new(key)
+            }),
+            features: std::sync::Arc::new(features),
+            body_template: std::env::var("WEBHOOK_BODY_TEMPLATE")
+                .ok()
+                .filter(|t| !t.trim().is_empty())
+                .map(|t| match BodyTemplate::parse(&t) {
+                    Ok(template) => std::sync::Arc::new(template),
+                    Err(reason) => panic!("WEBHOOK_BODY_TEMPLATE is invalid: {}", reason),
+                }),
+            batching: BatchSchedule::from_env(),
+            batches: std::sync::Arc::new(DashMap::new()),
+            open_batches: std::sync::Arc::new(DashMap::new()),
+            grace_until: std::env::var("WEBHOOK_STARTUP_GRACE_MS")
+                .ok()
+                .and_then(|v| v.parse::<u64>().ok())
+                .filter(|&ms| ms > 0)
+                .map(|ms| tokio::time::Instant::now() + Duration::from_millis(ms)),
+            sink_attempts: std::sync::Arc::new(DashMap::new()),
+            recorder: std::env::var("WEBHOOK_RECORD").ok().map(|path| {
+                let file = std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&path)
+                    .expect("open WEBHOOK_RECORD");
+                std::sync::Arc::new(std::sync::Mutex::new(file))
+            }),
+        }
+    }
+
+    fn feature_enabled(&self, name: &str) -> bool {
+        self.features.contains(name)
+    }
+
+    /// Installs a chaos profile and reseeds the chaos RNG from it.
+    fn set_chaos(&self, profile: ChaosProfile) {
+        *self.chaos_rng.lock().unwrap() = match profile.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_os_rng(),
+        };
+        *self.chaos.write().unwrap() = profile;
+    }
+
+    /// True with probability `rate`, drawn from the chaos RNG.
+    fn chaos_roll(&self, rate: f64) -> bool {
+        rate > 0.0 && self.chaos_rng.lock().unwrap().random_bool(rate)
+    }
+
+    /// Fault to inject into the next delivery attempt, if any.
+    fn chaos_webhook_fault(&self) -> Option<ChaosFault> {
+        let WebhookChaos { drop_rate, failure_rate } = self.chaos.read().unwrap().webhooks.clone();
+        if drop_rate + failure_rate <= 0.0 {
+            return None;
+        }
+        let draw: f64 = self.chaos_rng.lock().unwrap().random();
+        if draw < drop_rate {
+            Some(ChaosFault::Dropped)
+        } else if draw < drop_rate + failure_rate {
+            Some(ChaosFault::Failed)
+        } else {
+            None
+        }
+    }
+
+    /// `retry` with the chaos profile's overrides applied.
+    fn effective_retry(&self) -> RetryConfig {
+        let overrides = self.chaos.read().unwrap().retry.clone();
+        let mut retry = self.retry.clone();
+        if let Some(n) = overrides.max_retries {
+            retry.max_retries = n.min(retry.max_retries_ceiling);
+        }
+        if let Some(ms) = overrides.base_delay_ms {
+            retry.base_delay = Duration::from_millis(ms);
+        }
+        if let Some(ms) = overrides.timeout_ms {
+            retry.timeout = Duration::from_millis(ms);
+        }
+        retry
+    }
+
+    /// Rejects test-only options unless `TEST_MODE=true`.
+    fn require_test_mode(&self, option: &str) -> Result<(), ApiError> {
+        if self.test_mode {
+            Ok(())
+        } else {
+            Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "test_mode_required",
+                format!("{} is only available when TEST_MODE=true", option),
+            ))
+        }
+    }
+
+    /// Whether the invoice reached a terminal status after transition `seq`,
+    /// making a delivery describing `seq` stale.
+    fn superseded(&self, id: &InvoiceId, seq: u64) -> bool {
+        self.invoices
+            .get(id)
+            .is_some_and(|inv| inv.history.iter().any(|t| t.seq > seq && t.to.is_terminal()))
+    }
+
+    /// Map key for an `Idempotency-Key`; with `IDEMPOTENCY_HASH_KEYS` the key is
+    /// stored as its SHA-256 so long keys don't grow the map.
+    fn idempotency_scope(&self, operation: &str, key: &str) -> IdempotencyScope {
+        let key = if self.idempotency_hash_keys { hex::encode(Sha256::digest(key.as_bytes())) } else { key.to_string() };
+        (operation.to_string(), key)
+    }
+
+    /// The stored entry when `key` was already used for `operation`, with any
+    /// `IDEMPOTENCY_ON_MATCH` warning headers for the replay.
+    fn idempotent_replay(
+        &self,
+        operation: &str,
+        key: &str,
+        body_hash: &str,
+    ) -> Result<Option<(IdempotencyEntry, HeaderMap)>, ApiError> {
+        let scope = self.idempotency_scope(operation, key);
+        let Some(existing) = self.idempotency.get(&scope).map(|e| e.value().clone()) else {
+            return Ok(None);
+        };
+        let mismatch = existing.body_hash != body_hash;
+        let mut resp_headers = HeaderMap::new();
+        match self.idempotency_on_match {
+            IdempotencyOnMatch::ErrorIfDifferent if mismatch => {
+                return Err(ApiError::new(
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    "idempotency_key_mismatch",
+                    format!("Idempotency-Key {} was already used with a different body", key),
+                ));
+            }
+            IdempotencyOnMatch::ReturnOriginalWithWarning if mismatch => {
+                resp_headers.insert("X-Idempotency-Body-Mismatch", axum::http::HeaderValue::from_static("true"));
+            }
+            _ => {}
+        }
+        Ok(Some((existing, resp_headers)))
+    }
+
+    /// Checks `Authorization: Bearer <ADMIN_TOKEN>`.
+    fn require_admin(&self, headers: &HeaderMap) -> Result<(), ApiError> {
+        let Some(expected) = &self.admin_token else {
+            return Err(ApiError::new(
+                StatusCode::FORBIDDEN,
+                "admin_token_not_configured",
+                "Set ADMIN_TOKEN to use this endpoint",
+            ));
+        };
+        let given = headers
+            .get(axum::http::header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .unwrap_or("");
+        // Compare digests so the check doesn't leak how much of the token matched.
+        if Sha256::digest(given.as_bytes()) == Sha256::digest(expected.as_bytes()) {
+            Ok(())
+        } else {
+            Err(ApiError::new(StatusCode::UNAUTHORIZED, "unauthorized", "Missing or invalid admin token"))
+        }
+    }
+
+    /// `X-Signature` value for a body under the configured rotation mode.
+    fn sign(&self, body: &str) -> String {
+        match self.signature_rotation {
+            SignatureRotation::Dual => self.webhook_secrets
+                .iter()
+                .map(|secret| hmac_hex(secret, body))
+                .collect::<Vec<_>>()
+                .join(","),
+            SignatureRotation::RoundRobin => {
+                let n = self.rotation_counter.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                hmac_hex(&self.webhook_secrets[n % self.webhook_secrets.len()], body)
+            }
+        }
+    }
+
+    /// Sets the invoice's status, appending to its history and to the global
+    /// event log. Callers hold the invoice's map entry (or own it before
+    /// insertion), so one invoice's events are logged in the order they happen;
+    /// the log write lock keeps `seq` gap-free across invoices.
+    fn apply_transition(&self, inv: &mut Invoice, to: InvoiceStatus) {
+        let from = (!inv.history.is_empty()).then(|| inv.status.clone());
+        let at = Utc::now();
+        let mut events = self.events.write().unwrap();
+        let seq = events.len() as u64 + 1;
+        events.push(InvoiceEvent {
+            cursor: event_cursor(at, seq),
+            seq,
+            at,
+            invoice_id: inv.id.clone(),
+            from: from.clone(),
+            to: to.clone(),
+        });
+        drop(events);
+        if to == InvoiceStatus::Paid && inv.batch_id.is_none() && self.batching.is_some() {
+            inv.batch_id = Some(self.add_to_batch(inv, at));
+        }
+        // Whoever schedules the next step sets it again.
+        inv.next_emit_at = None;
+        if !matches!(to, InvoiceStatus::Created | InvoiceStatus::Queued) {
+            inv.scheduled_emit = None;
+        }
+        if to == InvoiceStatus::Authorized {
+            inv.capture_expires_at = inv.capture_expires_in_ms.map(|ms| {
+                at + chrono::Duration::from_std(self.scaled(Duration::from_millis(ms))).unwrap_or(chrono::TimeDelta::MAX)
+            });
+            inv.next_emit_at = inv.capture_expires_at;
+        }
+        inv.history.push(Transition { seq, at, from, to: to.clone() });
+        inv.status = to;
+        inv.updated_at = at;
+    }
+
+    /// Adds a paid invoice to the open batch of each of its webhook URLs,
+    /// opening any that are missing, and returns the primary's batch id.
+    fn add_to_batch(&self, inv: &Invoice, at: DateTime<Utc>) -> String {
+        let mut primary = None;
+        for url in inv.webhook_targets() {
+            let batch_id = self.add_to_url_batch(url, inv, at);
+            primary.get_or_insert(batch_id);
+        }
+        primary.unwrap_or_default()
+    }
+
+    fn add_to_url_batch(&self, url: &str, inv: &Invoice, at: DateTime<Utc>) -> String {
+        // The open-batch entry stays locked while the batch is updated, so
+        // `close_batches` can't close it halfway through.
+        let entry = self.open_batches.entry(url.to_string()).or_insert_with(|| {
+            let id = format!("batch_{}", Uuid::new_v4().simple());
+            self.batches.insert(id.clone(), SettlementBatch {
+                id: id.clone(),
+                webhook_url: url.to_string(),
+                status: BatchStatus::Open,
+                opened_at: at,
+                closed_at: None,
+                count: 0,
+                totals: std::collections::BTreeMap::new(),
+                invoice_ids: Vec::new(),
+            });
+            id
+        });
+        if let Some(mut batch) = self.batches.get_mut(entry.value()) {
+            batch.count += 1;
+            *batch.totals.entry(inv.currency.clone()).or_default() += inv.captured_amount.unwrap_or(inv.amount);
+            batch.invoice_ids.push(inv.id.clone());
+        }
+        entry.value().clone()
+    }
+
+    /// `X-Signature` and, with `WEBHOOK_SIGN_TIMESTAMP`, the `X-Timestamp` it
+    /// covers (signed as `<timestamp>.<body>`). `offset_secs` skews only the
+    /// timestamp, never the payload's `emitted_at`.
+    fn sign_webhook(&self, body: &str, secret: Option<&str>, offset_secs: i64) -> (String, Option<String>) {
+        let now = (Utc::now().timestamp() + offset_secs).to_string();
+        let timestamp = self.sign_timestamp.then(|| now.clone());
+        let signed = match (self.signature_scheme, &timestamp) {
+            (SignatureScheme::V2, _) => v2_signed_string(&now, body),
+            (SignatureScheme::Legacy, Some(ts)) => format!("{}.{}", ts, body),
+            (SignatureScheme::Legacy, None) => body.to_string(),
+        };
+        let sig = match secret {
+            Some(secret) => hmac_hex(secret, &signed),
+            None => self.sign(&signed),
+        };
+        let sig = match self.signature_scheme {
+            SignatureScheme::V2 => {
+                let slots: Vec<String> = sig.split(',').map(|s| format!("v1={}", s)).collect();
+                format!("scheme=v2,t={},{}", now, slots.join(","))
+            }
+            SignatureScheme::Legacy => sig,
+        };
+        (sig, timestamp)
+    }
+
+    /// Waits for the URL's turn in strict-order mode.
+    async fn take_url_turn(&self, url: &str) -> tokio::sync::OwnedMutexGuard<()> {
+        let turn = self.url_turns.entry(url.to_string()).or_default().clone();
+        *self.url_turn_waiting.entry(url.to_string()).or_insert(0) += 1;
+        let guard = turn.lock_owned().await;
+        if let Some(mut waiting) = self.url_turn_waiting.get_mut(url) {
+            *waiting -= 1;
+        }
+        self.url_turn_waiting.remove_if(url, |_, waiting| *waiting == 0);
+        guard
+    }
+
+    /// Takes one of the URL's outstanding-delivery slots, or `None` when it
+    /// is already at `WEBHOOK_MAX_OUTSTANDING_PER_URL`.
+    fn acquire_outstanding(&self, url: &str) -> Option<OutstandingSlot> {
+        let mut count = self.outstanding.entry(url.to_string()).or_insert(0);
+        if self.max_outstanding_per_url.is_some_and(|max| *count >= max) {
+            return None;
+        }
+        *count += 1;
+        Some(OutstandingSlot { outstanding: self.outstanding.clone(), url: url.to_string() })
+    }
+
+    /// `WEBHOOK_SECRETS_BY_HOST` entry for the URL's `host:port`, or else its
+    /// bare host.
+    fn secret_for_host(&self, url: &str) -> Option<String> {
+        if self.secrets_by_host.is_empty() {
+            return None;
+        }
+        let parsed = reqwest::Url::parse(url).ok()?;
+        let host = parsed.host_str()?.to_ascii_lowercase();
+        parsed
+            .port()
+            .and_then(|port| self.secrets_by_host.get(&format!("{}:{}", host, port)))
+            .or_else(|| self.secrets_by_host.get(&host))
+            .cloned()
+    }
+
+    /// Event id for `event` about the invoice's latest transition.
+    fn event_id(&self, inv: &Invoice, event: &str) -> String {
+        let seq = inv.history.last().map_or(0, |t| t.seq);
+        match self.event_id_strategy {
+            EventIdStrategy::Hash => {
+                let digest = Sha256::digest(format!("{}:{}:{}", inv.id, seq, event));
+                format!("evt_{}", &hex::encode(digest)[..24])
+            }
+            EventIdStrategy::Readable => format!("{}:{}:{}", inv.id, seq, event),
+        }
+    }
+
+    /// Counts one invoice against the tenant's limits, or explains which one
+    /// it would exceed. Tenants without an entry in `TENANT_QUOTAS` are not
+    /// limited.
+    fn consume_tenant_quota(&self, tenant: &str, now: DateTime<Utc>) -> Result<(), ApiError> {
+        let Some(quota) = self.tenant_quotas.get(tenant) else { return Ok(()) };
+        let mut usage = self.tenant_usage.entry(tenant.to_string()).or_insert_with(|| TenantUsage::new(now));
+        usage.roll(now);
+        if quota.daily_quota.is_some_and(|limit| usage.day_count >= limit) {
+            return Err(ApiError::new(
+                StatusCode::FORBIDDEN,
+                "quota_exceeded",
+                format!("Tenant {} used its daily invoice quota", tenant),
+            )
+            .with_detail("reset_at", serde_json::json!(usage.day_resets_at())));
+        }
+        if quota.rate_per_min.is_some_and(|limit| usage.minute_count >= limit) {
+            return Err(ApiError::new(
+                StatusCode::TOO_MANY_REQUESTS,
+                "rate_limited",
+                format!("Tenant {} is over its per-minute rate", tenant),
+            )
+            .with_detail("reset_at", serde_json::json!(usage.minute_resets_at())));
+        }
+        usage.minute_count += 1;
+        usage.day_count += 1;
+        Ok(())
+    }
+
+    /// When a scheduled task started now must be done waiting; `None` if the
+    /// ceiling is too far out to represent.
+    fn task_deadline(&self) -> Option<tokio::time::Instant> {
+        tokio::time::Instant::now().checked_add(self.max_task_lifetime)
+    }
+
+    /// Compresses (or stretches) a scheduling delay by `TIME_SCALE`.
+    fn scaled(&self, delay: Duration) -> Duration {
+        delay.div_f64(self.time_scale)
+    }
+
+    /// Locale and offset an invoice's formatted fields use: its own overrides,
+    /// else the server defaults.
+    fn formatting<'a>(&'a self, inv: &'a Invoice) -> (&'a str, chrono::FixedOffset) {
+        (
+            inv.locale.as_deref().unwrap_or(&self.locale),
+            inv.tz.as_deref().and_then(parse_utc_offset).unwrap_or(self.timezone),
+        )
+    }
+
+    fn checkout_url(&self, id: &InvoiceId) -> String {
+        match &self.checkout_base_url {
+            Some(base) => format!("{}/invoice/{}", base, id),
+            None => format!("https://checkout.local/invoice/{}", id),
+        }
+    }
+
+    /// Where the invoice resource lives; relative unless `CHECKOUT_BASE_URL` is set.
+    fn invoice_location(&self, id: &InvoiceId) -> axum::http::HeaderValue {
+        let path = format!("{}/invoices/{}", self.checkout_base_url.as_deref().unwrap_or(""), id);
+        axum::http::HeaderValue::from_str(&path).expect("invoice location is a valid header value")
+    }
+
+    fn metadata_url(&self, id: &InvoiceId) -> String {
+        format!("{}/invoices/{}/metadata", self.checkout_base_url.as_deref().unwrap_or(""), id)
+    }
+
+    /// Parses an invoice id from a request path according to `ID_STRATEGY`.
+    fn parse_invoice_id(&self, raw: &str) -> Result<InvoiceId, ApiError> {
+        self.id_strategy.parse(raw).ok_or_else(|| {
+            ApiError::new(StatusCode::BAD_REQUEST, "invalid_invoice_id", format!("{} is not a valid invoice id", raw))
+        })
+    }
+}
+
+// ===== Helpers =====
+
+/// HS256 JWT whose claims are the payload's fields plus `iat` and `jti`. A body
+/// that isn't a JSON object goes under a `data` claim.
+fn webhook_jwt(secret: &str, body: &str, jti: &str) -> String {
+    use base64::Engine;
+    let b64 = base64::engine::general_purpose::URL_SAFE_NO_PAD;
+
+    let mut claims = match serde_json::from_str::<serde_json::Value>(body) {
+        Ok(serde_json::Value::Object(map)) => map,
+        Ok(other) => serde_json::Map::from_iter([("data".to_string(), other)]),
+        Err(_) => serde_json::Map::from_iter([("data".to_string(), serde_json::Value::String(body.to_string()))]),
+    };
+    claims.insert("iat".into(), serde_json::json!(Utc::now().timestamp()));
+    claims.insert("jti".into(), serde_json::json!(jti));
+
+    let header = b64.encode(br#"{"alg":"HS256","typ":"JWT"}"#);
+    let payload = b64.encode(serde_json::Value::Object(claims).to_string());
+    let signing_input = format!("{}.{}", header, payload);
+    let mut mac = <Hmac<Sha256>>::new_from_slice(secret.as_bytes()).expect("hmac key");
+    mac.update(signing_input.as_bytes());
+    format!("{}.{}", signing_input, b64.encode(mac.finalize().into_bytes()))
+}
+
+/// `Content-Digest` value (RFC 9530) for a body: `sha-256=:<base64>:`.
+fn content_digest(body: &str) -> String {
+    use base64::Engine;
+    format!("sha-256=:{}:", base64::engine::general_purpose::STANDARD.encode(Sha256::digest(body.as_bytes())))
+}
+
+fn hmac_hex(secret: &str, body: &str) -> String {
+    let mut mac = <Hmac<Sha256>>::new_from_slice(secret.as_bytes()).expect("hmac key");
+    mac.update(body.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// What a `v2` signature covers.
+fn v2_signed_string(timestamp: &str, body: &str) -> String {
+    format!("v2.{}.{}", timestamp, body)
+}
+
+/// Checks a `scheme=...,t=...,v1=...` signature header against `body`. The
+/// header must name `expected_scheme`, so a consumer that expects `v2` can't
+/// be talked into accepting anything else, and `t` must be within
+/// `tolerance_secs` of `now`.
+fn verify_signature(
+    header: &str,
+    body: &str,
+    secret: &str,
+    expected_scheme: &str,
+    tolerance_secs: i64,
+    now: i64,
+) -> Result<(), String> {
+    let mut scheme = None;
+    let mut timestamp = None;
+    let mut signatures = Vec::new();
+    for part in header.split(',') {
+        match part.trim().split_once('=') {
+            Some(("scheme", v)) => scheme = Some(v),
+            Some(("t", v)) => timestamp = Some(v),
+            Some(("v1", v)) => signatures.push(v),
+            _ => {}
+        }
+    }
+    let scheme = scheme.unwrap_or("legacy");
+    if scheme != expected_scheme {
+        return Err(format!("scheme mismatch: expected {}, header has {}", expected_scheme, scheme));
+    }
+    if scheme != "v2" {
+        return Err(format!("unsupported scheme {}", scheme));
+    }
+    let timestamp = timestamp.ok_or("missing t")?;
+    let t: i64 = timestamp.parse().map_err(|_| format!("invalid t {:?}", timestamp))?;
+    if (now - t).abs() > tolerance_secs {
+        return Err(format!("t is {}s away from now, tolerance is {}s", (now - t).abs(), tolerance_secs));
+    }
+    let signed = v2_signed_string(timestamp, body);
+    let valid = signatures.iter().any(|sig| {
+        let Ok(sig) = hex::decode(sig) else { return false };
+        let mut mac = <Hmac<Sha256>>::new_from_slice(secret.as_bytes()).expect("hmac key");
+        mac.update(signed.as_bytes());
+        mac.verify_slice(&sig).is_ok()
+    });
+    if valid { Ok(()) } else { Err("no v1 signature matches".into()) }
+}
+
+/// Host (plus explicit port) of a webhook URL; unparseable URLs are kept verbatim
+/// so typos still show up as their own entry.
+fn webhook_host(url: &str) -> String {
+    match reqwest::Url::parse(url) {
+        Ok(u) => match (u.host_str(), u.port()) {
+            (Some(h), Some(p)) => format!("{}:{}", h, p),
+            (Some(h), None) => h.to_string(),
+            (None, _) => url.to_string(),
+        },
+        Err(_) => url.to_string(),
+    }
+}
+
+/// Symbol and number of minor-unit digits for an ISO 4217 code.
+fn currency_info(code: &str) -> Option<(&'static str, u32)> {
+    Some(match code {
+        "BRL" => ("R$", 2),
+        "USD" => ("US$", 2),
+        "EUR" => ("€", 2),
+        "GBP" => ("£", 2),
+        "ARS" => ("AR$", 2),
+        "MXN" => ("MX$", 2),
+        "CLP" => ("CLP$", 0),
+        "JPY" => ("¥", 0),
+        _ => return None,
+    })
+}
+
+/// Thousands separator, decimal separator and whether the symbol goes after the
+/// number, per supported locale.
+fn locale_format(locale: &str) -> Option<(&'static str, &'static str, bool)> {
+    Some(match locale {
+        "pt-BR" => (".", ",", false),
+        "en-US" => (",", ".", false),
+        "es-ES" | "de-DE" => (".", ",", true),
+        "fr-FR" => (" ", ",", true),
+        _ => return None,
+    })
+}
+
+/// Parses a fixed UTC offset: `UTC`, `Z` or `±HH:MM`.
+fn parse_utc_offset(s: &str) -> Option<chrono::FixedOffset> {
+    if s.eq_ignore_ascii_case("utc") || s == "Z" {
+        return chrono::FixedOffset::east_opt(0);
+    }
+    let sign = match s.as_bytes().first()? {
+        b'+' => 1,
+        b'-' => -1,
+        _ => return None,
+    };
+    let (hours, minutes) = s[1..].split_once(':')?;
+    if hours.len() != 2 || minutes.len() != 2 {
+        return None;
+    }
+    let (hours, minutes): (i32, i32) = (hours.parse().ok()?, minutes.parse().ok()?);
+    if hours > 23 || minutes > 59 {
+        return None;
+    }
+    chrono::FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60))
+}
+
+/// Formats an amount in minor units for display, e.g. `1990` BRL in `pt-BR`
+/// becomes `"R$ 19,90"`. Unknown currencies fall back to the ISO code with two
+/// decimal places.
+fn format_amount(amount: u64, currency: &str, locale: &str) -> String {
+    let code = currency.to_ascii_uppercase();
+    let (symbol, digits) = match currency_info(&code) {
+        Some((symbol, digits)) => (symbol.to_string(), digits),
+        None => (code, 2),
+    };
+    let (thousands, decimal, symbol_after) = locale_format(locale).unwrap_or((".", ",", false));
+
+    let divisor = 10u64.pow(digits);
+    let major = (amount / divisor).to_string();
+    let mut grouped = String::new();
+    for (i, c) in major.chars().enumerate() {
+        if i > 0 && (major.len() - i) % 3 == 0 {
+            grouped.push_str(thousands);
+        }
+        grouped.push(c);
+    }
+    let number = if digits > 0 {
+        format!("{}{}{:0width$}", grouped, decimal, amount % divisor, width = digits as usize)
+    } else {
+        grouped
+    };
+
+    if symbol_after {
+        format!("{} {}", number, symbol)
+    } else {
+        format!("{} {}", symbol, number)
+    }
+}
+
+fn map_emit_status(s: &EmitStatus, rng: &mut StdRng) -> InvoiceStatus {
+    match s {
+        EmitStatus::Paid => InvoiceStatus::Paid,
+        EmitStatus::Failed => InvoiceStatus::Failed,
+        EmitStatus::Canceled => InvoiceStatus::Canceled,
+        EmitStatus::Expired => InvoiceStatus::Expired,
+        EmitStatus::Chargeback => InvoiceStatus::Chargeback,
+        EmitStatus::Authorized => InvoiceStatus::Authorized,
+        EmitStatus::Random => {
+            let outcomes = [
+                InvoiceStatus::Paid,
+                InvoiceStatus::Failed,
+                InvoiceStatus::Canceled,
+                InvoiceStatus::Expired,
+                InvoiceStatus::Chargeback,
+            ];
+            outcomes[rng.random_range(0..outcomes.len())].clone()
+        }
+    }
+}
+
+/// POSTs the create request to `ENRICH_URL` and returns the JSON object the
+/// service answered with.
+async fn call_enrichment(
+    state: &AppState,
+    cfg: &EnrichConfig,
+    payload: &CreateInvoice,
+) -> Result<serde_json::Map<String, serde_json::Value>, String> {
+    state.counters.http_requests.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    let res = state.client.post(&cfg.url).json(payload).timeout(cfg.timeout).send().await.map_err(|e| {
+        if e.is_timeout() {
+            format!("enrichment timed out after {} ms", cfg.timeout.as_millis())
+        } else {
+            format!("enrichment request failed: {}", e)
+        }
+    })?;
+    if !res.status().is_success() {
+        return Err(format!("enrichment service answered {}", res.status()));
+    }
+    match res.json::<serde_json::Value>().await {
+        Ok(serde_json::Value::Object(fields)) => Ok(fields),
+        Ok(_) => Err("enrichment response is not a JSON object".to_string()),
+        Err(e) => Err(format!("reading enrichment response: {}", e)),
+    }
+}
+
+/// Overlays the enrichment fields on the create request; they win over what
+/// the client sent.
+fn apply_enrichment(
+    payload: &CreateInvoice,
+    fields: &serde_json::Map<String, serde_json::Value>,
+) -> Result<CreateInvoice, String> {
+    let mut merged = serde_json::to_value(payload).map_err(|e| e.to_string())?;
+    if let Some(obj) = merged.as_object_mut() {
+        obj.extend(fields.clone());
+    }
+    serde_json::from_value(merged).map_err(|e| format!("enrichment response does not fit the invoice: {}", e))
+}
+
+/// Proves the consumer owns `url` by sending a random challenge and requiring it
+/// to be echoed back in the configured shape.
+async fn run_verification_handshake(state: &AppState, url: &str) -> Result<(), String> {
+    let challenge = hex::encode(rand::random::<[u8; 16]>());
+
+    let req = match state.verify.method {
+        VerifyMethod::Get => state.client.get(url).query(&[("challenge", &challenge)]),
+        VerifyMethod::Post => {
+            let body = serde_json::json!({ "event": "webhook.verification", "challenge": challenge }).to_string();
+            let (sig, timestamp) = state.sign_webhook(&body, state.secret_for_host(url).as_deref(), 0);
+            let req = state.client
+                .post(url)
+                .header("Content-Type", state.webhook_content_type)
+                .header("X-Event", "webhook.verification")
+                .header("X-Signature", sig);
+            let req = match &state.acquirer_id {
+                Some(acquirer_id) => req.header("X-Acquirer-Id", acquirer_id),
+                None => req,
+            };
+            match timestamp {
+                Some(ts) => req.header("X-Timestamp", ts),
+                None => req,
+            }
+            .body(body)
+        }
+    };
+
+    state.counters.http_requests.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    let res = req
+        .timeout(state.verify.timeout)
+        .send()
+        .await
+        .map_err(|e| format!("handshake request failed: {}", e))?;
+    if !res.status().is_success() {
+        return Err(format!("consumer answered {} to the handshake", res.status()));
+    }
+    let text = res.text().await.map_err(|e| format!("reading handshake response: {}", e))?;
+
+    let echoed = match state.verify.expect {
+        VerifyExpect::Body => text.trim() == challenge,
+        VerifyExpect::Json => serde_json::from_str::<serde_json::Value>(&text)
+            .ok()
+            .and_then(|v| v.get("challenge").and_then(|c| c.as_str()).map(|c| c == challenge))
+            .unwrap_or(false),
+    };
+    if echoed {
+        Ok(())
+    } else {
+        Err("consumer did not echo the challenge back".to_string())
+    }
+}
+
+// ===== Delivery =====
+
+/// Waits out the emit delay, applies the final status and delivers
+/// `invoice.updated`.
+async fn emit_scheduled(state: AppState, id: InvoiceId, delay: Duration, final_status: InvoiceStatus) {
+    let deadline = state.task_deadline();
+    emit_scheduled_within(state, id, delay, final_status, deadline).await;
+}
+
+/// `emit_scheduled` for a task that started earlier and must be done waiting
+/// by `deadline`.
+async fn emit_scheduled_within(
+    state: AppState,
+    id: InvoiceId,
+    delay: Duration,
+    final_status: InvoiceStatus,
+    deadline: Option<tokio::time::Instant>,
+) {
+    if !wait_scheduled(&state, &id, delay, deadline).await {
+        return;
+    }
+    let inv = match transition_pending(&state, &id, final_status) {
+        Some(inv) => inv,
+        None => {
+            info!(%id, "invoice missing or no longer pending; scheduled emit skipped");
+            return;
+        }
+    };
+    if let (InvoiceStatus::Authorized, Some(ms)) = (&inv.status, inv.capture_expires_in_ms) {
+        tokio::spawn(expire_authorization(state.clone(), id.clone(), state.scaled(Duration::from_millis(ms))));
+    }
+    let event = if inv.status == InvoiceStatus::Review { "invoice.review" } else { "invoice.updated" };
+    emit_event(&state, &inv, event).await;
+}
+
+/// Runs the emit of a `deliver_sync` create and waits for it, for at most one
+/// attempt's timeout, then reports the attempts made so far.
+async fn deliver_inline(state: &AppState, id: &InvoiceId, final_status: InvoiceStatus) -> SyncDelivery {
+    let task = tokio::spawn(emit_scheduled(state.clone(), id.clone(), Duration::ZERO, final_status));
+    let completed = tokio::time::timeout(state.effective_retry().timeout, task).await.is_ok();
+    let attempts = state.deliveries.read().unwrap().iter().filter(|r| r.invoice_id == *id).cloned().collect();
+    SyncDelivery { completed, attempts }
+}
+
+/// Holds a `Queued` invoice for `wait`, then moves it to `Created` and runs the
+/// usual scheduled emit.
+async fn process_queued(state: AppState, id: InvoiceId, wait: Duration, notify: bool, delay: Duration, final_status: InvoiceStatus) {
+    let deadline = state.task_deadline();
+    if !wait_scheduled(&state, &id, wait, deadline).await {
+        return;
+    }
+    let inv = {
+        let Some(mut inv) = state.invoices.get_mut(&id) else { return };
+        if inv.status != InvoiceStatus::Queued {
+            info!(%id, "invoice no longer queued; processing skipped");
+            return;
+        }
+        state.apply_transition(&mut inv, InvoiceStatus::Created);
+        inv.next_emit_at = Some(Utc::now() + delay);
+        inv.clone()
+    };
+    if notify {
+        emit_event(&state, &inv, "invoice.updated").await;
+    }
+    emit_scheduled_within(state, id, delay, final_status, deadline).await;
+}
+
+/// Sleeps `wait` unless that would run past `deadline`. Then it only sleeps
+/// until the deadline, expires the invoice with `scheduler_ceiling` if it is
+/// still pending, and returns `false`: a safety net for absurd delays, not
+/// the normal expiry.
+async fn wait_scheduled(state: &AppState, id: &InvoiceId, wait: Duration, deadline: Option<tokio::time::Instant>) -> bool {
+    let wake = tokio::time::Instant::now().checked_add(wait);
+    let deadline = match deadline {
+        Some(deadline) if wake.map_or(true, |wake| wake > deadline) => deadline,
+        _ => {
+            sleep(wait).await;
+            return true;
+        }
+    };
+    tokio::time::sleep_until(deadline).await;
+    let inv = {
+        let Some(mut inv) = state.invoices.get_mut(id) else { return false };
+        if !matches!(inv.status, InvoiceStatus::Created | InvoiceStatus::Queued) {
+            return false;
+        }
+        inv.expired_reason = Some(ExpiredReason::SchedulerCeiling);
+        state.apply_transition(&mut inv, InvoiceStatus::Expired);
+        inv.clone()
+    };
+    warn!(%id, lifetime_secs = state.max_task_lifetime.as_secs(), "scheduled task hit its lifetime ceiling; invoice expired");
+    emit_event(state, &inv, "invoice.updated").await;
+    false
+}
+
+/// Closes every open settlement batch at the end of each window.
+async fn run_settlement_batches(state: AppState, schedule: BatchSchedule) {
+    loop {
+        let close_at = schedule.next_close(Utc::now());
+        sleep((close_at - Utc::now()).to_std().unwrap_or_default()).await;
+        close_batches(&state).await;
+    }
+}
+
+/// Closes all open batches and delivers one `settlement.batch.closed` per batch
+/// to its webhook URL.
+async fn close_batches(state: &AppState) {
+    let urls: Vec<String> = state.open_batches.iter().map(|e| e.key().clone()).collect();
+    for url in urls {
+        let Some((_, batch_id)) = state.open_batches.remove(&url) else { continue };
+        let batch = {
+            let Some(mut batch) = state.batches.get_mut(&batch_id) else { continue };
+            batch.status = BatchStatus::Closed;
+            batch.closed_at = Some(Utc::now());
+            batch.clone()
+        };
+        info!(batch_id = %batch.id, count = batch.count, url = %batch.webhook_url, "settlement batch closed");
+        let body = serde_json::json!({
+            "event": "settlement.batch.closed",
+            "batch_id": batch.id,
+            "opened_at": batch.opened_at,
+            "closed_at": batch.closed_at,
+            "count": batch.count,
+            "totals": batch.totals,
+            "invoice_ids": batch.invoice_ids,
+            "emitted_at": Utc::now(),
+        })
+        .to_string();
+        let state = state.clone();
+        tokio::spawn(async move {
+            let id = InvoiceId(batch.id.clone());
+            let opts = DeliveryOptions { event_id: Some(batch.id.clone()), ..Default::default() };
+            deliver(&state, &id, "settlement.batch.closed", &batch.webhook_url, body, &opts).await;
+        });
+    }
+}
+
+/// Voids an authorization left uncaptured for `window` and delivers
+/// `invoice.authorization.expired`.
+async fn expire_authorization(state: AppState, id: InvoiceId, window: Duration) {
+    sleep(window).await;
+    let inv = {
+        let Some(mut inv) = state.invoices.get_mut(&id) else { return };
+        if inv.status != InvoiceStatus::Authorized {
+            return;
+        }
+        state.apply_transition(&mut inv, InvoiceStatus::Voided);
+        inv.clone()
+    };
+    info!(%id, "authorization expired uncaptured; voided");
+    emit_event(&state, &inv, "invoice.authorization.expired").await;
+}
+
+/// Moves a `Created` invoice to `to`. Returns the updated invoice, or `None` if
+/// it does not exist or already left `Created` (e.g. it was force-expired).
+fn transition_pending(state: &AppState, id: &InvoiceId, to: InvoiceStatus) -> Option<Invoice> {
+    let mut inv = state.invoices.get_mut(id)?;
+    if !matches!(inv.status, InvoiceStatus::Created) {
+        return None;
+    }
+    state.apply_transition(&mut inv, to);
+    Some(inv.clone())
+}
+
+/// Builds the webhook for the invoice's current state and delivers it, unless
+/// the invoice's amount filter suppresses it. With `EMIT_CLOSED_EVENT`, the
+/// first terminal webhook is followed by `invoice.closed`.
+async fn emit_event(state: &AppState, inv: &Invoice, event: &'static str) -> bool {
+    let delivered = send_event(state, inv, event, None, None).await;
+    if state.emit_closed_event && inv.status.is_terminal() {
+        if let Some((closed, lifecycle)) = claim_close(state, &inv.id) {
+            send_event(state, &closed, "invoice.closed", Some(lifecycle), None).await;
+        }
+    }
+    delivered
+}
+
+/// Marks the invoice closed unless it already was, returning it along with
+/// its lifecycle summary. Done under the entry lock so a dispute that lands
+/// the invoice in another terminal status can't close it a second time.
+fn claim_close(state: &AppState, id: &InvoiceId) -> Option<(Invoice, Lifecycle)> {
+    let mut inv = state.invoices.get_mut(id)?;
+    if inv.closed_at.is_some() || !inv.status.is_terminal() {
+        return None;
+    }
+    let closed_at = Utc::now();
+    inv.closed_at = Some(closed_at);
+    inv.updated_at = closed_at;
+    let total_attempts = state.deliveries.read().unwrap().iter().filter(|r| r.invoice_id == *id).count();
+    let lifecycle = Lifecycle { created_at: inv.created_at, closed_at, history_length: inv.history.len(), total_attempts };
+    Some((inv.clone(), lifecycle))
+}
+
+/// Sends `invoice.viewed` if this is the first read of an `emit_on_view`
+/// invoice; `via` says which endpoint it was.
+fn record_view(state: &AppState, id: &InvoiceId, via: &'static str, headers: &HeaderMap, peer: Option<SocketAddr>) {
+    let Some((inv, viewed_at)) = claim_view(state, id) else { return };
+    let viewer = Viewer::from_request(state.viewer_masking, headers, peer);
+    let state = state.clone();
+    tokio::spawn(async move {
+        let event_id = state.event_id(&inv, "invoice.viewed");
+        let body = ViewedPayload {
+            event: "invoice.viewed",
+            event_id: event_id.clone(),
+            id: inv.id.clone(),
+            status: inv.status.clone(),
+            viewed_at,
+            via,
+            viewer,
+        };
+        let json_body = match serde_json::to_string(&body) {
+            Ok(s) => s,
+            Err(e) => {
+                error!(error = %e, "serialize webhook body");
+                return;
+            }
+        };
+        let opts = DeliveryOptions::for_event(&state, &inv, event_id);
+        deliver_to_invoice(&state, &inv, "invoice.viewed", json_body, &opts).await;
+    });
+}
+
+/// Marks the invoice viewed unless it already was. Done under the entry lock,
+/// like `claim_close`, so concurrent GETs send `invoice.viewed` only once.
+fn claim_view(state: &AppState, id: &InvoiceId) -> Option<(Invoice, DateTime<Utc>)> {
+    let mut inv = state.invoices.get_mut(id)?;
+    if !inv.emit_on_view || inv.viewed_at.is_some() {
+        return None;
+    }
+    let viewed_at = Utc::now();
+    inv.viewed_at = Some(viewed_at);
+    inv.updated_at = viewed_at;
+    Some((inv.clone(), viewed_at))
+}
+
+/// Does the work of `emit_event` for a single event.
+async fn send_event(
+    state: &AppState,
+    inv: &Invoice,
+    event: &'static str,
+    lifecycle: Option<Lifecycle>,
+    occurred_at: Option<DateTime<Utc>>,
+) -> bool {
+    if let Some(threshold) = inv.emit_only_if_amount_gte {
+        if inv.amount < threshold {
+            info!(id = %inv.id, amount = inv.amount, threshold, outcome = "suppressed_by_filter", "webhook not emitted");
+            return false;
+        }
+    }
+
+    let event_id = state.event_id(inv, event);
+    let decline_code = (inv.status == InvoiceStatus::Failed).then(|| inv.decline_code.unwrap_or_default());
+    let (locale, tz) = state.formatting(inv);
+    let emitted_at = Utc::now();
+    let by_reference = state
+        .metadata_inline_max_bytes
+        .is_some_and(|max| serde_json::to_string(&inv.metadata).map_or(0, |m| m.len()) > max);
+    let body = WebhookPayload {
+        event,
+        event_id: event_id.clone(),
+        seq: inv.history.last().map_or(0, |t| t.seq),
+        id: inv.id.clone(),
+        status: inv.status.clone(),
+        amount: inv.amount,
+        amount_formatted: format_amount(inv.amount, &inv.currency, locale),
+        auth_amount: inv.auth_amount,
+        captured_amount: inv.captured_amount,
+        risk_score: inv.risk_score,
+        decline_code,
+        decline_message: decline_code.map(DeclineCode::message),
+        currency: inv.currency.clone(),
+        emitted_at,
+        emitted_at_local: emitted_at.with_timezone(&tz),
+        locale: locale.to_string(),
+        tz: tz.to_string(),
+        metadata: (!by_reference).then(|| inv.metadata.clone()),
+        metadata_url: by_reference.then(|| state.metadata_url(&inv.id)),
+        requires_action: inv.requires_action.clone(),
+        lifecycle,
+        occurred_at,
+        compat: state.compat_fields.then(|| CompatFields {
+            kind: event,
+            invoice_id: inv.id.clone(),
+            state: inv.status.clone(),
+        }),
+    };
+
+    let invoice_template = inv.webhook_body_template.as_deref().and_then(|t| BodyTemplate::parse(t).ok());
+    let template = invoice_template.as_ref().or(state.body_template.as_deref());
+    let serialized = match template {
+        Some(template) => serde_json::to_value(&body).map(|payload| template.render(&payload)),
+        None => match &inv.webhook_field_order {
+            Some(order) => serde_json::to_value(&body).map(|payload| render_in_order(&payload, order)),
+            // `serde_json::Map` keeps keys sorted, so the round trip sorts them.
+            None if state.feature_enabled("canonical_json") => serde_json::to_value(&body).map(|payload| payload.to_string()),
+            None => serde_json::to_string(&body),
+        },
+    };
+    let json_body = match serialized {
+        Ok(s) => s,
+        Err(e) => {
+            error!(error = %e, "serialize webhook body");
+            return false;
+        }
+    };
+
+    // `inv` is read at send time, so a PATCH made while the emit was pending is honored.
+    info!(url = %inv.webhook_url, status = ?body.status, "emitting webhook");
+    let opts = DeliveryOptions {
+        seq: inv.history.last().map(|t| t.seq),
+        ..DeliveryOptions::for_event(state, inv, event_id)
+    };
+    deliver_to_invoice(state, inv, event, json_body, &opts).await
+}
+
+/// Delivers an invoice event to `webhook_url`, or per `webhook_delivery_mode`
+/// when the invoice has `webhook_urls`. `true` if it reached every URL it was
+/// meant for.
+async fn deliver_to_invoice(state: &AppState, inv: &Invoice, event: &'static str, body: String, opts: &DeliveryOptions) -> bool {
+    match (inv.webhook_delivery_mode, inv.webhook_urls.as_slice()) {
+        (Some(WebhookDeliveryMode::Fanout), urls @ [_, ..]) => {
+            let deliveries = urls.iter().map(|url| deliver(state, &inv.id, event, url, body.clone(), opts));
+            futures_util::future::join_all(deliveries).await.iter().all(|r| r.outcome == DeliveryOutcome::Delivered)
+        }
+        (Some(WebhookDeliveryMode::Failover), [earlier @ .., last]) => {
+            for url in earlier {
+                // Not dead-lettered: the next URL takes over instead, so a
+                // redrive can't deliver it twice.
+                let record = deliver_with_retries(state, &inv.id, event, url, body.clone(), opts).await;
+                if record.outcome != DeliveryOutcome::DeadLettered {
+                    return record.outcome == DeliveryOutcome::Delivered;
+                }
+                warn!(id = %inv.id, event, %url, "webhook URL gave up; failing over to the next one");
+            }
+            deliver(state, &inv.id, event, last, body, opts).await.outcome == DeliveryOutcome::Delivered
+        }
+        _ => deliver(state, &inv.id, event, &inv.webhook_url, body, opts).await.outcome == DeliveryOutcome::Delivered,
+    }
+}
+
+/// Top-level `WebhookPayload` fields a body template may reference.
+const TEMPLATE_FIELDS: &[&str] = &[
+    "event", "event_id", "seq", "id", "status", "amount", "amount_formatted", "auth_amount",
+    "captured_amount", "risk_score", "decline_code", "decline_message", "currency", "emitted_at", "emitted_at_local", "locale", "tz",
+    "metadata", "metadata_url", "requires_action", "lifecycle", "occurred_at",
+];
+
+/// Checks a `webhook_field_order`: payload fields only, each at most once.
+fn validate_field_order(order: &[String]) -> Result<(), String> {
+    for (i, name) in order.iter().enumerate() {
+        if !TEMPLATE_FIELDS.contains(&name.as_str()) {
+            return Err(format!("unknown payload field {:?}", name));
+        }
+        if order[..i].contains(name) {
+            return Err(format!("payload field {:?} is listed twice", name));
+        }
+    }
+    Ok(())
+}
+
+/// Writes the payload object with the `order` fields first, as listed (those
+/// absent from this payload are skipped), then the others in their usual
+/// order. Done by hand since `serde_json::Map` keeps keys sorted.
+fn render_in_order(payload: &serde_json::Value, order: &[String]) -> String {
+    let Some(fields) = payload.as_object() else { return payload.to_string() };
+    let known = || TEMPLATE_FIELDS.iter().chain(COMPAT_FIELD_NAMES);
+    let rest = known()
+        .map(|name| name.to_string())
+        .chain(fields.keys().filter(|k| !known().any(|name| name == k)).cloned())
+        .filter(|name| !order.contains(name));
+    let mut out = String::from("{");
+    for name in order.iter().cloned().chain(rest) {
+        let Some(value) = fields.get(&name) else { continue };
+        if out.len() > 1 {
+            out.push(',');
+        }
+        out.push_str(&serde_json::Value::String(name).to_string());
+        out.push(':');
+        out.push_str(&value.to_string());
+    }
+    out.push('}');
+    out
+}
+
+/// A custom webhook body: literal text with `{{field}}` placeholders, where
+/// `field` is a payload field optionally followed by a dotted path into it
+/// (`{{metadata.order_id}}`). Each placeholder becomes the JSON encoding of
+/// that value, or `null` when absent, so placeholders stand for whole JSON
+/// values rather than parts of a string.
+#[derive(Debug, Clone)]
+struct BodyTemplate {
+    parts: Vec<TemplatePart>,
+}
+
+#[derive(Debug, Clone)]
+enum TemplatePart {
+    Literal(String),
+    Field(Vec<String>),
+}
+
+impl BodyTemplate {
+    fn parse(source: &str) -> Result<Self, String> {
+        let mut parts = Vec::new();
+        let mut rest = source;
+        while let Some(start) = rest.find("{{") {
+            parts.push(TemplatePart::Literal(rest[..start].to_string()));
+            let after = &rest[start + 2..];
+            let end = after.find("}}").ok_or("unterminated {{ placeholder")?;
+            let name = after[..end].trim();
+            let path: Vec<String> = name.split('.').map(str::to_string).collect();
+            if !TEMPLATE_FIELDS.contains(&path[0].as_str()) || path.iter().any(|p| p.is_empty()) {
+                return Err(format!("unknown placeholder {{{{{}}}}}", name));
+            }
+            parts.push(TemplatePart::Field(path));
+            rest = &after[end + 2..];
+        }
+        parts.push(TemplatePart::Literal(rest.to_string()));
+
+        // Placeholders always render as one JSON value, so if rendering each as
+        // `""` parses, every real render does too (and a placeholder quoted
+        // inside a string literal is caught).
+        let template = BodyTemplate { parts };
+        serde_json::from_str::<serde_json::Value>(&template.render_with(|_| "\"\"".to_string()))
+            .map_err(|e| format!("template does not render valid JSON: {}", e))?;
+        Ok(template)
+    }
+
+    fn render(&self, payload: &serde_json::Value) -> String {
+        self.render_with(|path| {
+            let value = path.iter().try_fold(payload, |v, key| v.get(key));
+            value.unwrap_or(&serde_json::Value::Null).to_string()
+        })
+    }
+
+    fn render_with(&self, field: impl Fn(&[String]) -> String) -> String {
+        let mut out = String::new();
+        for part in &self.parts {
+            match part {
+                TemplatePart::Literal(text) => out.push_str(text),
+                TemplatePart::Field(path) => out.push_str(&field(path)),
+            }
+        }
+        out
+    }
+}
+
+/// Per-delivery knobs, usually taken from the invoice.
+#[derive(Debug, Clone, Default)]
+struct DeliveryOptions {
+    chunked: bool,
+    /// Overrides `WEBHOOK_ACCEPT`.
+    accept: Option<String>,
+    /// Overrides `WEBHOOK_EXPECT_CONTINUE`.
+    expect_continue: Option<bool>,
+    /// Signs with this secret instead of the configured ones.
+    secret: Option<String>,
+    /// Overrides `WEBHOOK_MAX_RETRIES`.
+    max_retries: Option<u32>,
+    /// Stop retrying once this much time has passed since the first attempt.
+    deadline: Option<Duration>,
+    /// Transition the payload describes; retries stop once the invoice makes
+    /// a newer terminal transition.
+    seq: Option<u64>,
+    /// Sent as `X-Event-Id` and kept on the delivery records.
+    event_id: Option<String>,
+    /// Skews `X-Timestamp` by this many seconds (test mode).
+    timestamp_offset_secs: i64,
+    /// Static headers from the invoice's registered consumer.
+    extra_headers: Vec<(String, String)>,
+    /// Compare `X-Signature-Echo` on 2xx responses with what was sent.
+    require_signature_echo: bool,
+}
+
+impl DeliveryOptions {
+    fn for_invoice(inv: &Invoice) -> Self {
+        Self {
+            chunked: inv.webhook_chunked,
+            accept: inv.webhook_accept.clone(),
+            expect_continue: inv.webhook_expect_continue,
+            require_signature_echo: inv.require_signature_echo,
+            deadline: inv.webhook_deadline_ms.map(Duration::from_millis),
+            timestamp_offset_secs: inv.sign_timestamp_offset_secs.unwrap_or(0),
+            max_retries: inv.max_retries,
+            ..Default::default()
+        }
+    }
+
+    /// `for_invoice` plus the event id and the consumer's secret and headers.
+    fn for_event(state: &AppState, inv: &Invoice, event_id: String) -> Self {
+        let mut opts = DeliveryOptions { event_id: Some(event_id), ..DeliveryOptions::for_invoice(inv) };
+        if let Some(consumer) = inv.consumer.as_ref().and_then(|name| state.consumers.get(name)) {
+            opts.secret = consumer.secret.clone();
+            opts.extra_headers = consumer.headers.iter().map(|(n, v)| (n.clone(), v.clone())).collect();
+        }
+        opts
+    }
+}
+
+/// Chunk size used when streaming bodies; small enough that typical payloads
+/// span several chunks.
+const WEBHOOK_CHUNK_SIZE: usize = 256;
+
+/// Renders an error with its sources, e.g. reqwest's "error following
+/// redirect" plus the reason the redirect was refused.
+fn error_chain(err: &dyn std::error::Error) -> String {
+    let mut msg = err.to_string();
+    let mut source = err.source();
+    while let Some(e) = source {
+        msg.push_str(": ");
+        msg.push_str(&e.to_string());
+        source = e.source();
+    }
+    msg
+}
+
+/// How much of a consumer's response body is read for `WEBHOOK_RETRY_ON_BODY_REGEX`.
+const RETRY_BODY_MAX_BYTES: usize = 8 * 1024;
+
+/// Reads up to `max` bytes of a response body (lossily decoded), stopping at the
+/// first read error.
+async fn read_body_capped(mut res: reqwest::Response, max: usize) -> String {
+    let mut buf = Vec::new();
+    while buf.len() < max {
+        match res.chunk().await {
+            Ok(Some(chunk)) => buf.extend_from_slice(&chunk),
+            _ => break,
+        }
+    }
+    buf.truncate(max);
+    String::from_utf8_lossy(&buf).into_owned()
+}
+
+/// Counts a delivery as outstanding for its URL until dropped.
+struct OutstandingSlot {
+    outstanding: std::sync::Arc<DashMap<String, usize>>,
+    url: String,
+}
+
+impl Drop for OutstandingSlot {
+    fn drop(&mut self) {
+        if let Some(mut count) = self.outstanding.get_mut(&self.url) {
+            *count = count.saturating_sub(1);
+        }
+        self.outstanding.remove_if(&self.url, |_, count| *count == 0);
+    }
+}
+
+/// Sends a signed webhook, retrying failures with exponential backoff, and
+/// records every attempt in the delivery log. Returns the final attempt. A
+/// dead-lettered webhook is kept for `POST /admin/deadletters/redrive-all`.
+async fn deliver(
+    state: &AppState,
+    invoice_id: &InvoiceId,
+    event: &'static str,
+    url: &str,
+    body: String,
+    opts: &DeliveryOptions,
+) -> DeliveryRecord {
+    let record = deliver_with_retries(state, invoice_id, event, url, body.clone(), opts).await;
+    if record.outcome == DeliveryOutcome::DeadLettered {
+        let mut letters = state.dead_letters.lock().unwrap();
+        if letters.len() >= MAX_DEAD_LETTERS {
+            letters.pop_front();
+        }
+        letters.push_back(DeadLetter {
+            invoice_id: invoice_id.clone(),
+            event,
+            url: url.to_string(),
+            host: record.host.clone(),
+            at: record.at,
+            body,
+            opts: opts.clone(),
+        });
+    }
+    record
+}
+
+async fn deliver_with_retries(
+    state: &AppState,
+    invoice_id: &InvoiceId,
+    event: &'static str,
+    url: &str,
+    body: String,
+    opts: &DeliveryOptions,
+) -> DeliveryRecord {
+    // Held for the whole delivery, grace period and retry backoff included.
+    let Some(_slot) = state.acquire_outstanding(url) else {
+        warn!(%invoice_id, event, url, "destination overloaded; webhook dead-lettered");
+        let record = DeliveryRecord {
+            invoice_id: invoice_id.clone(),
+            event: event.to_string(),
+            event_id: opts.event_id.clone(),
+            attempt: 1,
+            max_retries: 0,
+            at: Utc::now(),
+            url: url.to_string(),
+            host: webhook_host(url),
+            outcome: DeliveryOutcome::DeadLettered,
+            status_code: None,
+            duration_ms: 0,
+            error: Some("too many outstanding deliveries to this URL".into()),
+            dead_letter_reason: Some(DeadLetterReason::DestinationOverloaded),
+            body_retry_match: None,
+            retry_rule: None,
+            signature_confirmed: None,
+            chaos: None,
+        };
+        log_attempt(state, &record, &[], &body);
+        return record;
+    };
+
+    // Strict order: wait for every earlier delivery to this URL, retries
+    // included, to finish.
+    let _turn = if state.strict_order_per_url { Some(state.take_url_turn(url).await) } else { None };
+
+    if let Some(until) = state.grace_until.filter(|&until| until > tokio::time::Instant::now()) {
+        info!(%invoice_id, event, "webhook held until the startup grace period ends");
+        tokio::time::sleep_until(until).await;
+    }
+
+    // An explicit secret (consumer, ping) beats the per-host one.
+    let secret = opts.secret.clone().or_else(|| state.secret_for_host(url));
+
+    // Like the signature, the JWT is minted once and reused by every retry.
+    let jwt = state.jwt.map(|placement| {
+        let secret = secret.as_deref().unwrap_or(&state.webhook_secrets[0]);
+        let jti = opts.event_id.clone().unwrap_or_else(|| Uuid::new_v4().to_string());
+        (placement, webhook_jwt(secret, &body, &jti))
+    });
+    let (body, content_type, bearer) = match jwt {
+        Some((JwtPlacement::Body, token)) => (token, "application/jwt", None),
+        Some((JwtPlacement::Header, token)) => (body, state.webhook_content_type, Some(format!("Bearer {}", token))),
+        None => (body, state.webhook_content_type, None),
+    };
+
+    // Computed once so every retry of this delivery carries the same signature.
+    let (sig, timestamp) = state.sign_webhook(&body, secret.as_deref(), opts.timestamp_offset_secs);
+    let digest = state.include_digest.then(|| content_digest(&body));
+    let ed25519_sig = state.ed25519_key.as_ref().map(|key| {
+        use base64::Engine;
+        base64::engine::general_purpose::STANDARD.encode(key.sign(body.as_bytes()))
+    });
+    let mut headers = vec![
+        ("Content-Type", content_type),
+        ("Accept", opts.accept.as_deref().unwrap_or(&state.webhook_accept)),
+        ("X-Event", event),
+        ("X-Signature", sig.as_str()),
+    ];
+    // Only the header: the body still goes out right away, without waiting
+    // for a `100 Continue`.
+    if opts.expect_continue.unwrap_or(state.webhook_expect_continue) {
+        headers.push(("Expect", "100-continue"));
+    }
+    if let Some(ts) = timestamp.as_deref() {
+        headers.push(("X-Timestamp", ts));
+    }
+    if let Some(event_id) = opts.event_id.as_deref() {
+        headers.push(("X-Event-Id", event_id));
+    }
+    if let Some(acquirer_id) = state.acquirer_id.as_deref() {
+        headers.push(("X-Acquirer-Id", acquirer_id));
+    }
+    if let Some(bearer) = bearer.as_deref() {
+        headers.push(("Authorization", bearer));
+    }
+    if let Some(digest) = digest.as_deref() {
+        headers.push(("Content-Digest", digest));
+    }
+    if let Some(sig) = ed25519_sig.as_deref() {
+        headers.push(("X-Signature-Ed25519", sig));
+    }
+    for (name, value) in &opts.extra_headers {
+        headers.push((name.as_str(), value.as_str()));
+    }
+    // Recorded once per delivery, not per attempt, so a replay doesn't repeat retries.
+    record_webhook(state, url, &headers, &body);
+
+    let host = webhook_host(url);
+    let retry = state.effective_retry();
+    let max_retries = opts.max_retries.unwrap_or(retry.max_retries);
+    let max_attempts = max_retries + 1;
+    let deadline = opts.deadline.map(|d| std::time::Instant::now() + d);
+
+    let mut attempt = 0;
+    let mut next_delay = Duration::ZERO;
+    loop {
+        attempt += 1;
+        if attempt > 1 {
+            sleep(state.scaled(next_delay)).await;
+            if opts.seq.is_some_and(|seq| state.superseded(invoice_id, seq)) {
+                info!(%invoice_id, event, attempt, "retry superseded by a newer terminal transition");
+                let record = DeliveryRecord {
+                    invoice_id: invoice_id.clone(),
+                    event: event.to_string(),
+                    event_id: opts.event_id.clone(),
+                    attempt,
+                    max_retries,
+                    at: Utc::now(),
+                    url: url.to_string(),
+                    host: host.clone(),
+                    outcome: DeliveryOutcome::Superseded,
+                    status_code: None,
+                    duration_ms: 0,
+                    error: Some("superseded by a newer terminal transition".into()),
+                    dead_letter_reason: None,
+                    body_retry_match: None,
+                    retry_rule: None,
+                    signature_confirmed: None,
+                    chaos: None,
+                };
+                log_attempt(state, &record, &headers, &body);
+                return record;
+            }
+        }
+
+        // Signed over the full bytes above; chunking only changes the framing.
+        let req_body = if opts.chunked || state.feature_enabled("chunked") {
+            let chunks: Vec<Result<Vec<u8>, std::io::Error>> =
+                body.as_bytes().chunks(WEBHOOK_CHUNK_SIZE).map(|c| Ok(c.to_vec())).collect();
+            reqwest::Body::wrap_stream(futures_util::stream::iter(chunks))
+        } else {
+            reqwest::Body::from(body.clone())
+        };
+
+        let started = std::time::Instant::now();
+        let timeout = match deadline {
+            Some(d) => retry.timeout.min(d.saturating_duration_since(started)),
+            None => retry.timeout,
+        };
+        let fault = state.chaos_webhook_fault();
+        let (status_code, err, body_retry_match, retry_after, signature_confirmed, duration_ms, retryable) = match fault {
+            Some(ChaosFault::Dropped) => (None, None, None, None, None, 0, true),
+            Some(ChaosFault::Failed) => {
+                (None, Some("failed by the chaos profile (not sent)".to_string()), None, None, None, 0, true)
+            }
+            #[cfg(feature = "grpc")]
+            None if url.starts_with("grpc://") => {
+                let message = grpc::WebhookEvent {
+                    event: event.to_string(),
+                    event_id: opts.event_id.clone().unwrap_or_default(),
+                    invoice_id: invoice_id.to_string(),
+                    body: body.clone(),
+                    headers: headers.iter().map(|(n, v)| (n.to_string(), v.to_string())).collect(),
+                };
+                let (err, retryable) = grpc::call(&state.grpc_channels, &state.grpc_method, url, message, timeout).await;
+                (None, err, None, None, None, started.elapsed().as_millis() as u64, retryable)
+            }
+            None => {
+                let mut req = state.client.post(url);
+                for (name, value) in &headers {
+                    req = req.header(*name, *value);
+                }
+                state.counters.http_requests.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                let res = req
+                    .body(req_body)
+                    .timeout(timeout)
+                    .send()
+                    .await;
+                let duration_ms = started.elapsed().as_millis() as u64;
+
+                let retry_after = res
+                    .as_ref()
+                    .ok()
+                    .and_then(|r| r.headers().get(axum::http::header::RETRY_AFTER))
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(|v| v.trim().parse::<u64>().ok());
+                let signature_confirmed = match &res {
+                    Ok(r) if opts.require_signature_echo && r.status().is_success() => {
+                        Some(r.headers().get("X-Signature-Echo").is_some_and(|echo| echo.as_bytes() == sig.as_bytes()))
+                    }
+                    _ => None,
+                };
+                if signature_confirmed == Some(false) {
+                    warn!(%invoice_id, attempt, "consumer did not echo the X-Signature it was sent");
+                }
+                let (status_code, err, body_retry_match) = match res {
+                    Ok(r) if r.status().is_success() => {
+                        let code = r.status().as_u16();
+                        match &retry.retry_on_body {
+                            Some(re) => {
+                                let matched = re.is_match(&read_body_capped(r, RETRY_BODY_MAX_BYTES).await);
+                                let err = matched.then(|| format!("consumer answered {} but its body asked for a retry", code));
+                                (Some(code), err, Some(matched))
+                            }
+                            None => (Some(code), None, None),
+                        }
+                    }
+                    Ok(r) if r.status().is_redirection() => {
+                        let location = r.headers().get("Location").and_then(|v| v.to_str().ok()).unwrap_or("-");
+                        let err = format!("consumer answered {} to {} (redirect not followed)", r.status(), location);
+                        (Some(r.status().as_u16()), Some(err), None)
+                    }
+                    Ok(r) => (Some(r.status().as_u16()), Some(format!("consumer answered {}", r.status())), None),
+                    Err(e) => (None, Some(error_chain(&e)), None),
+                };
+                (status_code, err, body_retry_match, retry_after, signature_confirmed, duration_ms, true)
+            }
+        };
+        let rule = err.as_ref().and_then(|_| retry.rule_for(status_code));
+        let (retry_budget, max_attempts) = match rule {
+            Some(rule) => {
+                let budget = opts.max_retries.map_or(rule.max_retries, |n| n.min(rule.max_retries))
+                    .min(retry.max_retries_ceiling);
+                (budget, budget + 1)
+            }
+            None => (max_retries, max_attempts),
+        };
+        next_delay = match (rule, retry_after) {
+            (Some(rule), Some(secs)) if rule.respect_retry_after => Duration::from_secs(secs.min(MAX_RETRY_AFTER_SECS)),
+            _ => retry.backoff(attempt),
+        };
+        let past_deadline = deadline.is_some_and(|d| std::time::Instant::now() + state.scaled(next_delay) >= d);
+        let (outcome, dead_letter_reason) = match &err {
+            None => (DeliveryOutcome::Delivered, None),
+            Some(_) if !retryable => (DeliveryOutcome::DeadLettered, Some(DeadLetterReason::NotRetryable)),
+            Some(_) if attempt >= max_attempts => (DeliveryOutcome::DeadLettered, Some(DeadLetterReason::RetriesExhausted)),
+            Some(_) if past_deadline => (DeliveryOutcome::DeadLettered, Some(DeadLetterReason::DeadlineExceeded)),
+            Some(_) => (DeliveryOutcome::Failed, None),
+        };
+
+        match &err {
+            None if fault == Some(ChaosFault::Dropped) => {
+                warn!(%invoice_id, attempt, "webhook dropped by the chaos profile; recorded as delivered")
+            }
+            None => info!(%invoice_id, attempt, status = ?status_code, "webhook delivered"),
+            Some(e) => error!(%invoice_id, attempt, outcome = ?outcome, error = %e, "webhook delivery failed"),
+        }
+
+        let record = DeliveryRecord {
+            invoice_id: invoice_id.clone(),
+            event: event.to_string(),
+            event_id: opts.event_id.clone(),
+            attempt,
+            max_retries: retry_budget,
+            at: Utc::now(),
+            url: url.to_string(),
+            host: host.clone(),
+            outcome,
+            status_code,
+            duration_ms,
+            error: err,
+            dead_letter_reason,
+            body_retry_match,
+            retry_rule: rule.map(|rule| rule.name.clone()),
+            signature_confirmed,
+            chaos: fault,
+        };
+        log_attempt(state, &record, &headers, &body);
+
+        if outcome != DeliveryOutcome::Failed {
+            return record;
+        }
+    }
+}
+
+/// Appends an attempt to the delivery log and the recent-deliveries buffer.
+fn log_attempt(state: &AppState, record: &DeliveryRecord, headers: &[(&str, &str)], body: &str) {
+    state.deliveries.write().unwrap().push(record.clone());
+    state.counters.deliveries[record.outcome as usize].fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    if state.recent_capacity > 0 {
+        let entry = RecentDelivery {
+            at: record.at,
+            invoice_id: record.invoice_id.clone(),
+            event: record.event.clone(),
+            attempt: record.attempt,
+            url: record.url.clone(),
+            outcome: record.outcome,
+            status_code: record.status_code,
+            duration_ms: record.duration_ms,
+            error: record.error.clone(),
+            headers: headers
+                .iter()
+                .map(|(n, v)| {
+                    let value = if is_sensitive_header(n) { "[redacted]" } else { v };
+                    (n.to_string(), value.to_string())
+                })
+                .collect(),
+            payload_preview: body.chars().take(RECENT_PREVIEW_CHARS).collect(),
+        };
+        let mut recent = state.recent.lock().unwrap();
+        if recent.len() >= state.recent_capacity {
+            recent.pop_front();
+        }
+        recent.push_back(entry);
+    }
+}
+
+// ===== gRPC delivery =====
+
+/// Refuses a `grpc://` webhook URL up front when the binary was built
+/// without the `grpc` feature, instead of letting every delivery fail.
+fn check_grpc_url(url: &str) -> Result<(), ApiError> {
+    if cfg!(not(feature = "grpc")) && url.starts_with("grpc://") {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "grpc_not_enabled",
+            "grpc:// webhook URLs need a build with --features grpc",
+        )
+        .with_detail("url", url));
+    }
+    Ok(())
+}
+
+/// Webhooks to `grpc://host:port` URLs, sent as a `WebhookService/Deliver`
+/// call (proto/webhook.proto). The messages are kept in sync with the proto
+/// by hand, so building needs no `protoc`.
+#[cfg(feature = "grpc")]
+mod grpc {
+    use std::time::Duration;
+
+    use dashmap::DashMap;
+    use tonic::{transport::Channel, Code};
+
+    /// Default for `WEBHOOK_GRPC_METHOD`.
+    pub const DELIVER_METHOD: &str = "/tickpay.webhook.v1.WebhookService/Deliver";
+
+    /// `tickpay.webhook.v1.WebhookEvent`.
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct WebhookEvent {
+        #[prost(string, tag = "1")]
+        pub event: String,
+        #[prost(string, tag = "2")]
+        pub event_id: String,
+        #[prost(string, tag = "3")]
+        pub invoice_id: String,
+        #[prost(string, tag = "4")]
+        pub body: String,
+        #[prost(map = "string, string", tag = "5")]
+        pub headers: std::collections::HashMap<String, String>,
+    }
+
+    /// `tickpay.webhook.v1.WebhookAck`.
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct WebhookAck {}
+
+    /// Makes one call; returns the error, if any, and whether it is worth
+    /// retrying. Connection failures and timeouts are.
+    pub async fn call(
+        channels: &DashMap<String, Channel>,
+        method: &str,
+        url: &str,
+        message: WebhookEvent,
+        timeout: Duration,
+    ) -> (Option<String>, bool) {
+        let authority = url.trim_start_matches("grpc://").split('/').next().unwrap_or_default();
+        let channel = match channels.get(authority) {
+            Some(channel) => channel.clone(),
+            None => match Channel::from_shared(format!("http://{}", authority)) {
+                Ok(endpoint) => channels.entry(authority.to_string()).or_insert_with(|| endpoint.connect_lazy()).clone(),
+                Err(e) => return (Some(format!("invalid gRPC URL: {}", e)), false),
+            },
+        };
+        let Ok(path) = method.parse() else { return (Some(format!("invalid gRPC method {:?}", method)), false) };
+
+        let mut client = tonic::client::Grpc::new(channel);
+        let call = async {
+            client.ready().await.map_err(|e| tonic::Status::unavailable(super::error_chain(&e)))?;
+            let mut request = tonic::Request::new(message);
+            request.set_timeout(timeout);
+            client.unary(request, path, tonic::codec::ProstCodec::<WebhookEvent, WebhookAck>::default()).await
+        };
+        match tokio::time::timeout(timeout, call).await {
+            Ok(Ok(_)) => (None, true),
+            Ok(Err(status)) => {
+                let err = format!("consumer answered {}: {}", code_name(status.code()), status.message());
+                (Some(err), retryable(status.code()))
+            }
+            Err(_) => (Some(format!("gRPC call timed out after {}ms", timeout.as_millis())), true),
+        }
+    }
+
+    /// Transient statuses, retried like a 5xx; the rest dead-letter at once.
+    fn retryable(code: Code) -> bool {
+        matches!(
+            code,
+            Code::Unavailable | Code::DeadlineExceeded | Code::ResourceExhausted | Code::Aborted | Code::Internal | Code::Unknown
+        )
+    }
+
+    /// `UNAVAILABLE`, `INVALID_ARGUMENT`...: the names the gRPC spec uses.
+    fn code_name(code: Code) -> String {
+        let mut name = String::new();
+        for (i, c) in format!("{:?}", code).chars().enumerate() {
+            if c.is_uppercase() && i > 0 {
+                name.push('_');
+            }
+            name.push(c.to_ascii_uppercase());
+        }
+        name
+    }
+}
+
+// ===== Replay =====
+
+/// One line of the `WEBHOOK_RECORD` file.
+#[derive(Debug, Serialize, Deserialize)]
+struct RecordedWebhook {
+    at: DateTime<Utc>,
+    url: String,
+    headers: Vec<(String, String)>,
+    body: String,
+}
+
+fn record_webhook(state: &AppState, url: &str, headers: &[(&str, &str)], body: &str) {
+    let Some(recorder) = &state.recorder else { return };
+    let line = RecordedWebhook {
+        at: Utc::now(),
+        url: url.to_string(),
+        headers: headers.iter().map(|(n, v)| (n.to_string(), v.to_string())).collect(),
+        body: body.to_string(),
+    };
+    let mut line = serde_json::to_string(&line).expect("serialize recorded webhook");
+    line.push('\n');
+    use std::io::Write;
+    if let Err(e) = recorder.lock().unwrap().write_all(line.as_bytes()) {
+        error!(error = %e, "write WEBHOOK_RECORD");
+    }
+}
+
+/// `replay <file> --to <url> [--speed <factor>]`: re-sends a `WEBHOOK_RECORD`
+/// session in its original order and relative timing (divided by `factor`),
+/// re-signed with the current secrets.
+async fn run_replay(state: AppState, args: &[String]) -> Result<(), String> {
+    const USAGE: &str = "usage: replay <file> --to <url> [--speed <factor>]";
+    let mut file = None;
+    let mut to = None;
+    let mut speed = 1.0f64;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--to" => to = Some(args.next().ok_or(USAGE)?.clone()),
+            "--speed" => {
+                speed = args.next().and_then(|v| v.parse().ok()).filter(|s: &f64| *s > 0.0).ok_or("--speed must be a positive number")?;
+            }
+            _ if file.is_none() && !arg.starts_with("--") => file = Some(arg.clone()),
+            _ => return Err(format!("unexpected argument {:?}\n{}", arg, USAGE)),
+        }
+    }
+    let (file, to) = file.zip(to).ok_or(USAGE)?;
+
+    let content = std::fs::read_to_string(&file).map_err(|e| format!("{}: {}", file, e))?;
+    let recorded = content
+        .lines()
+        .enumerate()
+        .filter(|(_, l)| !l.trim().is_empty())
+        .map(|(i, l)| serde_json::from_str::<RecordedWebhook>(l).map_err(|e| format!("{}:{}: {}", file, i + 1, e)))
+        .collect::<Result<Vec<_>, _>>()?;
+    let Some(first_at) = recorded.first().map(|w| w.at) else {
+        return Err(format!("{}: no recorded webhooks", file));
+    };
+
+    let secret = state.secret_for_host(&to);
+    let started = tokio::time::Instant::now();
+    let mut failures = 0usize;
+    for webhook in &recorded {
+        let offset = (webhook.at - first_at).to_std().unwrap_or_default().div_f64(speed);
+        tokio::time::sleep_until(started + offset).await;
+
+        let mut req = state.client.post(&to);
+        let resigned = ["X-Signature", "X-Timestamp"];
+        for (name, value) in webhook.headers.iter().filter(|(n, _)| !resigned.iter().any(|r| n.eq_ignore_ascii_case(r))) {
+            req = req.header(name, value);
+        }
+        let (sig, timestamp) = state.sign_webhook(&webhook.body, secret.as_deref(), 0);
+        if let Some(ts) = timestamp {
+            req = req.header("X-Timestamp", ts);
+        }
+        let event = webhook.headers.iter().find(|(n, _)| n.eq_ignore_ascii_case("X-Event")).map_or("-", |(_, v)| v.as_str());
+        let res = req
+            .header("X-Signature", sig)
+            .body(webhook.body.clone())
+            .timeout(state.retry.timeout)
+            .send()
+            .await;
+        match res {
+            Ok(r) if r.status().is_success() => println!("{} {}", r.status().as_u16(), event),
+            Ok(r) => {
+                failures += 1;
+                println!("{} {}", r.status().as_u16(), event);
+            }
+            Err(e) => {
+                failures += 1;
+                println!("ERR {} ({})", event, e);
+            }
+        }
+    }
+
+    println!("replayed {} webhooks to {}, {} failed", recorded.len(), to, failures);
+    Ok(())
+}
+
+/// `verify --signature <header> [--secret <s>] [--scheme v2] [--tolerance-secs <n>] [<body-file>]`:
+/// checks a received `X-Signature` against the body (from the file, or
+/// stdin). The secret defaults to the primary configured one.
+fn run_verify(state: &AppState, args: &[String]) -> Result<(), String> {
+    const USAGE: &str = "usage: verify --signature <header> [--secret <s>] [--scheme v2] [--tolerance-secs <n>] [<body-file>]";
+    let mut header = None;
+    let mut secret = None;
+    let mut scheme = "v2".to_string();
+    let mut tolerance_secs = 300i64;
+    let mut file = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--signature" => header = Some(args.next().ok_or(USAGE)?.clone()),
+            "--secret" => secret = Some(args.next().ok_or(USAGE)?.clone()),
+            "--scheme" => scheme = args.next().ok_or(USAGE)?.clone(),
+            "--tolerance-secs" => {
+                tolerance_secs = args.next().and_then(|v| v.parse().ok()).filter(|t: &i64| *t >= 0).ok_or("--tolerance-secs must be a non-negative number")?;
+            }
+            _ if file.is_none() && !arg.starts_with("--") => file = Some(arg.clone()),
+            _ => return Err(format!("unexpected argument {:?}\n{}", arg, USAGE)),
+        }
+    }
+    let header = header.ok_or(USAGE)?;
+    let body = match &file {
+        Some(file) => std::fs::read_to_string(file).map_err(|e| format!("{}: {}", file, e))?,
+        None => std::io::read_to_string(std::io::stdin()).map_err(|e| format!("stdin: {}", e))?,
+    };
+    let secret = secret.unwrap_or_else(|| state.webhook_secrets[0].clone());
+    verify_signature(&header, &body, &secret, &scheme, tolerance_secs, Utc::now().timestamp())?;
+    println!("ok");
+    Ok(())
+}
+
+// ===== Routes =====
+
+/// How forgiving routing is about path variations (`PATH_NORMALIZATION`).
+#[derive(Debug, Clone, Copy)]
+enum PathNormalization {
+    Strict,
+    /// `/invoices/` routes like `/invoices`.
+    TrailingSlash,
+    /// Trailing slashes plus case-insensitive static segments (`/Invoices`).
+    CaseInsensitive,
+}
+
+/// Static path segments of the routes below; keep in sync when adding routes.
+/// Dynamic segments (ids) are left untouched since some id strategies are
+/// case-sensitive.
+const ROUTE_SEGMENTS: &[&str] = &[
+    "invoices", "invoice", "notes", "events-emitted", "capture", "authenticate", "dispute", "resolve", "events", "since", "features", "webhooks", "ping", "admin",
+    "webhook-urls", "delivery-stats", "deliveries", "deliveries.csv", "recent", "expire-pending", "_seed", "_sink", "debug", "echo", "batches", "readyz", "report", "tenants", "snapshot", "restore", "metrics", "redeliver-all", "deadletters", "redrive-all", "redrives", "metadata", "review",
+];
+
+/// Delays each request by the chaos profile's `api.latency_ms`, if any.
+async fn chaos_latency(
+    State(state): State<AppState>,
+    req: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    let latency = state.chaos.read().unwrap().api.latency_ms;
+    if let Some(LatencyRange { min, max }) = latency {
+        let ms = state.chaos_rng.lock().unwrap().random_range(min..=max);
+        sleep(Duration::from_millis(ms)).await;
+    }
+    next.run(req).await
+}
+
+/// Re-reads `CHAOS_PROFILE` on every SIGHUP. A file that no longer loads is
+/// logged and the running profile kept.
+async fn reload_chaos_on_sighup(state: AppState, path: String) {
+    let mut hangups = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+        Ok(signal) => signal,
+        Err(e) => {
+            error!(error = %e, "cannot listen for SIGHUP; CHAOS_PROFILE will not be reloaded");
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        match ChaosProfile::load(&path) {
+            Ok(profile) => {
+                info!(path, ?profile, "chaos profile reloaded");
+                state.set_chaos(profile);
+            }
+            Err(reason) => error!(%reason, "chaos profile reload failed; keeping the previous one"),
+        }
+    }
+}
+
+/// With `ERROR_FORMAT=problem_json`, rewrites every error response as an RFC
+/// 7807 `application/problem+json` document. `ApiError`s map `error` to
+/// `type` (`urn:tickpay:error:<error>`), `message` to `detail` and keep their
+/// extra fields as extension members; other errors (extractor rejections, the
+/// unknown-route 404) use their reason phrase and text body. `instance` is
+/// the request path.
+async fn problem_json(req: axum::extract::Request, next: axum::middleware::Next) -> axum::response::Response {
+    let instance = req.uri().path().to_string();
+    let response = next.run(req).await;
+    let status = response.status();
+    if !status.is_client_error() && !status.is_server_error() {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let reason = status.canonical_reason().unwrap_or("Error");
+    let mut problem = serde_json::Map::new();
+    match parts.extensions.remove::<std::sync::Arc<ApiError>>() {
+        Some(err) => {
+            problem.extend(err.details.clone());
+            problem.insert("type".into(), format!("urn:tickpay:error:{}", err.error).into());
+            problem.insert("detail".into(), err.message.clone().into());
+        }
+        None => {
+            let Ok(bytes) = axum::body::to_bytes(body, usize::MAX).await else {
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            };
+            let code = reason.to_ascii_lowercase().replace(' ', "_");
+            problem.insert("type".into(), format!("urn:tickpay:error:{}", code).into());
+            let detail = String::from_utf8_lossy(&bytes).trim().to_string();
+            if !detail.is_empty() {
+                problem.insert("detail".into(), detail.into());
+            }
+        }
+    }
+    problem.insert("title".into(), reason.into());
+    problem.insert("status".into(), status.as_u16().into());
+    problem.insert("instance".into(), instance.into());
+
+    parts.headers.remove(axum::http::header::CONTENT_LENGTH);
+    parts.headers.insert(
+        axum::http::header::CONTENT_TYPE,
+        axum::http::HeaderValue::from_static("application/problem+json"),
+    );
+    axum::response::Response::from_parts(parts, axum::body::Body::from(serde_json::Value::Object(problem).to_string()))
+}
+
+/// Rewraps JSON responses as `{"data", "error", "meta"}`: successes go under
+/// `data`, errors under `error` (plain-text rejections from the extractors
+/// become `{error, message}` first), and `meta` carries the request id (the
+/// caller's `X-Request-Id`, or a fresh one) plus `next_cursor` on paginated
+/// bodies. Anything else (HTML, CSV, metrics) passes through untouched.
+async fn api_envelope(req: axum::extract::Request, next: axum::middleware::Next) -> axum::response::Response {
+    use axum::http::header::{CONTENT_LENGTH, CONTENT_TYPE};
+    let request_id = req
+        .headers()
+        .get("x-request-id")
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| !v.is_empty())
+        .map(str::to_string)
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+
+    let (mut parts, body) = next.run(req).await.into_parts();
+    let content_type = parts.headers.get(CONTENT_TYPE).and_then(|v| v.to_str().ok()).unwrap_or("");
+    let is_json = content_type.starts_with("application/json") || content_type.starts_with("application/problem+json");
+    let is_error = parts.status.is_client_error() || parts.status.is_server_error();
+    let is_text_error = is_error && content_type.starts_with("text/plain");
+    if !is_json && !is_text_error {
+        return axum::response::Response::from_parts(parts, body);
+    }
+    let Ok(bytes) = axum::body::to_bytes(body, usize::MAX).await else {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    };
+
+    let payload = if is_json {
+        serde_json::from_slice(&bytes).unwrap_or(serde_json::Value::Null)
+    } else {
+        let reason = parts.status.canonical_reason().unwrap_or("error").to_ascii_lowercase().replace(' ', "_");
+        serde_json::json!({ "error": reason, "message": String::from_utf8_lossy(&bytes).trim() })
+    };
+    let mut meta = serde_json::json!({ "request_id": request_id });
+    if let Some(cursor) = payload.get("next_cursor") {
+        meta["next_cursor"] = cursor.clone();
+    }
+    let envelope = if is_error {
+        serde_json::json!({ "data": null, "error": payload, "meta": meta })
+    } else {
+        serde_json::json!({ "data": payload, "error": null, "meta": meta })
+    };
+
+    parts.headers.remove(CONTENT_LENGTH);
+    parts.headers.insert(CONTENT_TYPE, axum::http::HeaderValue::from_static("application/json"));
+    if let Ok(value) = axum::http::HeaderValue::from_str(&request_id) {
+        parts.headers.insert("x-request-id", value);
+    }
+    axum::response::Response::from_parts(parts, axum::body::Body::from(envelope.to_string()))
+}
+
+async fn lowercase_route_segments(mut req: axum::extract::Request) -> axum::extract::Request {
+    let uri = req.uri();
+    let path = uri
+        .path()
+        .split('/')
+        .map(|seg| {
+            let lower = seg.to_ascii_lowercase();
+            if ROUTE_SEGMENTS.contains(&lower.as_str()) { lower } else { seg.to_string() }
+        })
+        .collect::<Vec<_>>()
+        .join("/");
+    if path != uri.path() {
+        let path_and_query = match uri.query() {
+            Some(q) => format!("{}?{}", path, q),
+            None => path,
+        };
+        let mut parts = uri.clone().into_parts();
+        if let Ok(pq) = path_and_query.parse() {
+            parts.path_and_query = Some(pq);
+            if let Ok(uri) = axum::http::Uri::from_parts(parts) {
+                *req.uri_mut() = uri;
+            }
+        }
+    }
+    req
+}
+
+/// The whole HTTP API, layers included, around `state`.
+fn router(state: AppState) -> Router {
+    let cors = CorsLayer::new()
+        .allow_methods(Any)
+        .allow_headers(Any)
+        .allow_origin(Any);
+
+    let app = Router::new()
+        .route("/invoices", get(list_invoices).post(create_invoice))
+        .route("/invoices/report", get(invoice_report))
+        .route("/invoices/:id", get(get_invoice).patch(update_invoice))
+        .route("/invoices/:id/notes", post(add_note))
+        .route("/invoices/:id/events-emitted", get(events_emitted))
+        .route("/invoices/:id/metadata", get(get_metadata))
+        .route("/invoices/:id/capture", post(capture_invoice))
+        .route("/invoices/:id/authenticate", post(authenticate_invoice))
+        .route("/invoices/:id/review", post(review_invoice))
+        .route("/invoices/:id/dispute", post(open_dispute))
+        .route("/invoices/:id/dispute/resolve", post(resolve_dispute))
+        .route("/invoices/:id/redeliver-all", post(redeliver_all))
+        .route("/events/since", get(events_since))
+        .route("/batches/:id", get(get_batch))
+        .route("/features", get(list_features))
+        .route("/readyz", get(readyz))
+        .route("/webhooks/ping", post(ping_webhook))
+        .route("/admin/webhook-urls", get(list_webhook_urls))
+        .route("/admin/delivery-stats", get(delivery_stats))
+        .route("/admin/deliveries", get(list_deliveries))
+        .route("/admin/deliveries.csv", get(deliveries_csv))
+        .route("/admin/recent", get(recent_deliveries))
+        .route("/metrics", get(metrics))
+        .route("/admin/tenants", get(tenant_usage))
+        .route("/admin/expire-pending", post(expire_pending))
+        .route("/admin/deadletters/redrive-all", post(redrive_dead_letters))
+        .route("/admin/deadletters/redrives/:id", get(get_redrive))
+        .route("/admin/snapshot", get(take_snapshot))
+        .route(
+            "/admin/restore",
+            post(restore_snapshot).layer(axum::extract::DefaultBodyLimit::max(MAX_RESTORE_BYTES)),
+        )
+        .route("/debug/echo", get(debug_echo).post(debug_echo))
+        .route("/_seed", post(seed_invoices))
+        .route("/_sink", post(sink))
+        .route("/_sink/*consumer", post(sink));
+    // Minimal hosted checkout page, for clicking through flows by hand.
+    let app = if std::env::var("UI_ENABLED").as_deref() == Ok("true") {
+        app.route("/invoice/:id", get(checkout_page))
+    } else {
+        app
+    };
+    let app = if state.chaos_path.is_some() {
+        app.layer(axum::middleware::from_fn_with_state(state.clone(), chaos_latency))
+    } else {
+        app
+    };
+    let envelope = state.feature_enabled("envelope");
+    let app = app.with_state(state);
+    let app = if std::env::var("ERROR_FORMAT").as_deref() == Ok("problem_json") {
+        app.layer(axum::middleware::from_fn(problem_json))
+    } else {
+        app
+    };
+    // SDK-style `{data, error, meta}` bodies, for clients that expect them.
+    let app = if envelope {
+        app.layer(axum::middleware::from_fn(api_envelope))
+    } else {
+        app
+    };
+    let app = app
+        .layer(TraceLayer::new_for_http())
+        .layer(cors);
+
+    let normalization = match std::env::var("PATH_NORMALIZATION").as_deref() {
+        Ok("strict") => PathNormalization::Strict,
+        Ok("case_insensitive") => PathNormalization::CaseInsensitive,
+        _ => PathNormalization::TrailingSlash,
+    };
+    // Path rewrites must happen before routing, so the app is wrapped as the
+    // fallback of an outer router instead of using `Router::layer`.
+    match normalization {
+        PathNormalization::Strict => app,
+        PathNormalization::TrailingSlash => {
+            Router::new().fallback_service(NormalizePath::trim_trailing_slash(app))
+        }
+        PathNormalization::CaseInsensitive => Router::new()
+            .fallback_service(NormalizePath::trim_trailing_slash(app))
+            .layer(axum::middleware::map_request(lowercase_route_segments)),
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    dotenvy::dotenv().ok();
+
+    tracing_subscriber::fmt()
+        .with_max_level(Level::INFO)
+        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+        .init();
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("replay") {
+        if let Err(e) = run_replay(AppState::from_env(), &args[1..]).await {
+            eprintln!("replay: {}", e);
+            std::process::exit(2);
+        }
+        return;
+    }
+    if args.first().map(String::as_str) == Some("verify") {
+        if let Err(e) = run_verify(&AppState::from_env(), &args[1..]) {
+            eprintln!("verify: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let state = AppState::from_env();
+    if let Some(path) = state.chaos_path.clone() {
+        match ChaosProfile::load(&path) {
+            Ok(profile) => {
+                info!(path, ?profile, "chaos profile loaded");
+                state.set_chaos(profile);
+            }
+            Err(reason) => panic!("CHAOS_PROFILE is invalid: {}", reason),
+        }
+        tokio::spawn(reload_chaos_on_sighup(state.clone(), path));
+    }
+    if state.get_lag > chrono::Duration::zero() {
+        if state.test_mode {
+            warn!(lag_ms = state.get_lag.num_milliseconds(), status = %state.get_lag_status, "GET_LAG_MS set; new invoices are hidden from GET /invoices/:id");
+        } else {
+            warn!("GET_LAG_MS ignored; it needs TEST_MODE=true");
+        }
+    }
+    if state.time_scale != 1.0 {
+        warn!(time_scale = state.time_scale, "TIME_SCALE set; emit delays, retry backoffs and expiries are divided by it");
+    }
+    if !state.features.is_empty() {
+        info!(features = ?state.features, "feature flags enabled");
+    }
+    if let Some(schedule) = state.batching {
+        info!(interval_secs = schedule.interval.as_secs(), "settlement batching enabled");
+        tokio::spawn(run_settlement_batches(state.clone(), schedule));
+    }
+
+    let app = router(state);
+
+    let port: u16 = std::env::var("PORT").ok().and_then(|v| v.parse().ok()).unwrap_or(8080);
+    let addr = SocketAddr::from(([0, 0, 0, 0], port));
+    let listener = TcpListener::bind(addr).await.expect("bind");
+    info!(addr = %listener.local_addr().unwrap(), "fake-acquirer listening");
+    // Peer addresses feed the `viewer` of `invoice.viewed`.
+    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
+        .await
+        .expect("server");
+}
+
+async fn create_invoice(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    JsonBody(mut payload): JsonBody<CreateInvoice>,
+) -> Result<(StatusCode, HeaderMap, axum::body::Bytes), ApiError> {
+    let create_failure_rate = state.chaos.read().unwrap().api.create_failure_rate;
+    if state.chaos_roll(create_failure_rate) {
+        return Err(ApiError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "chaos_create_failure",
+            "Create failed on purpose (chaos profile)",
+        ));
+    }
+
+    let body_hash = hex::encode(Sha256::digest(serde_json::to_vec(&payload).unwrap_or_default()));
+
+    // Idempotency (optional): replays get the original body, with `200` and
+    // `Content-Location` instead of `201` and `Location`.
+    let idempotency_key = idempotency_key(&headers);
+    if let Some(key) = &idempotency_key {
+        if let Some((existing, mut resp_headers)) = state.idempotent_replay("create", key, &body_hash)? {
+            resp_headers.insert(axum::http::header::CONTENT_LOCATION, state.invoice_location(&existing.invoice_id));
+            return Ok(json_bytes(StatusCode::OK, resp_headers, existing.response));
+        }
+    }
+
+    let tenant = headers
+        .get("x-tenant-id")
+        .and_then(|v| v.to_str().ok())
+        .map(str::trim)
+        .filter(|t| !t.is_empty())
+        .map(str::to_string);
+
+    // Synchronous enrichment, before anything reads the request fields.
+    let mut enrichment = None;
+    if let Some(cfg) = &state.enrich {
+        let started = tokio::time::Instant::now();
+        let enriched = match call_enrichment(&state, cfg, &payload).await {
+            Ok(fields) => apply_enrichment(&payload, &fields).map(|p| (p, fields)),
+            Err(reason) => Err(reason),
+        };
+        let elapsed_ms = started.elapsed().as_millis() as u64;
+        match enriched {
+            Ok((enriched, fields)) => {
+                info!(elapsed_ms, "invoice enriched");
+                payload = enriched;
+                enrichment = Some(serde_json::Value::Object(fields));
+            }
+            Err(reason) if cfg.fail_closed => {
+                return Err(ApiError::new(StatusCode::BAD_GATEWAY, "enrichment_failed", reason)
+                    .with_detail("elapsed_ms", elapsed_ms));
+            }
+            Err(reason) => warn!(%reason, elapsed_ms, "enrichment failed; creating the invoice without it"),
+        }
+    }
+
+    // A declined create never reaches delivery, so it doesn't need a webhook URL.
+    if payload.decline_at_create {
+        state.require_test_mode("decline_at_create")?;
+        let code = payload.decline_code.unwrap_or_default();
+        info!(decline_code = ?code, "declining invoice at creation");
+        return Err(ApiError::new(StatusCode::PAYMENT_REQUIRED, "card_declined", "The payment was declined")
+            .with_detail("decline_code", serde_json::json!(code))
+            .with_detail("decline_message", code.message()));
+    }
+
+    match &payload.webhook_urls {
+        Some(urls) => {
+            let valid = (1..=MAX_WEBHOOK_URLS).contains(&urls.len())
+                && urls.iter().enumerate().all(|(i, url)| !url.trim().is_empty() && !urls[..i].contains(url));
+            if !valid {
+                return Err(ApiError::new(
+                    StatusCode::BAD_REQUEST,
+                    "invalid_webhook_urls",
+                    format!("webhook_urls takes 1 to {} distinct, non-empty URLs", MAX_WEBHOOK_URLS),
+                ));
+            }
+            if !payload.webhook_url.trim().is_empty() || payload.consumer.is_some() {
+                return Err(ApiError::new(
+                    StatusCode::BAD_REQUEST,
+                    "conflicting_webhook_target",
+                    "Send only one of webhook_url, webhook_urls or consumer",
+                ));
+            }
+            payload.webhook_url = urls[0].clone();
+        }
+        None if payload.webhook_delivery_mode.is_some() => {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_webhook_urls",
+                "webhook_delivery_mode only applies with webhook_urls",
+            ));
+        }
+        None => {}
+    }
+
+    if let Some(name) = &payload.consumer {
+        let consumer = state.consumers.get(name).ok_or_else(|| {
+            ApiError::new(StatusCode::BAD_REQUEST, "unknown_consumer", format!("No consumer named {} is registered", name))
+        })?;
+        if !payload.webhook_url.trim().is_empty() {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "conflicting_webhook_target",
+                "Send either webhook_url or consumer, not both",
+            ));
+        }
+        payload.webhook_url = consumer.url.clone();
+    }
+
+    if payload.webhook_url.trim().is_empty() {
+        match &state.default_webhook_url {
+            Some(url) => payload.webhook_url = url.clone(),
+            None => {
+                return Err(ApiError::new(
+                    StatusCode::BAD_REQUEST,
+                    "webhook_url_required",
+                    "webhook_url is required when DEFAULT_WEBHOOK_URL is not set",
+                ));
+            }
+        }
+    }
+    check_grpc_url(&payload.webhook_url)?;
+    for url in payload.webhook_urls.iter().flatten() {
+        check_grpc_url(url)?;
+    }
+
+    if let Some(deadline_ms) = payload.webhook_deadline_ms {
+        let first_retry_ms = state.retry.base_delay.as_millis() as u64;
+        if deadline_ms < first_retry_ms {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_webhook_deadline",
+                format!("webhook_deadline_ms must be at least the first retry delay ({}ms)", first_retry_ms),
+            ));
+        }
+    }
+
+    if payload.risk_score.is_some_and(|score| score > 100) {
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, "invalid_risk_score", "risk_score must be between 0 and 100"));
+    }
+
+    if payload.sign_timestamp_offset_secs.is_some() {
+        state.require_test_mode("sign_timestamp_offset_secs")?;
+        if !state.sign_timestamp {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "timestamp_signing_disabled",
+                "sign_timestamp_offset_secs requires WEBHOOK_SIGN_TIMESTAMP=true",
+            ));
+        }
+    }
+
+    if let Some(Err(reason)) = payload.emit_delay_model.map(|model| model.validate()) {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_emit_delay_model",
+            format!("emit_delay_model: {}", reason),
+        ));
+    }
+
+    if payload.process_after_ms.is_some_and(|ms| ms > MAX_PROCESS_AFTER_MS) {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_process_after_ms",
+            format!("process_after_ms must be at most {}", MAX_PROCESS_AFTER_MS),
+        ));
+    }
+
+    if payload.external_id.as_ref().is_some_and(|ext| ext.is_empty() || ext.len() > MAX_EXTERNAL_ID_LEN) {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_external_id",
+            format!("external_id must be 1 to {} bytes", MAX_EXTERNAL_ID_LEN),
+        ));
+    }
+
+    if payload.deliver_sync {
+        state.require_test_mode("deliver_sync")?;
+        let immediate = payload.emit_after_ms == 0
+            && payload.emit_jitter_ms == 0
+            && payload.emit_delay_model.is_none()
+            && payload.process_after_ms.is_none();
+        if !immediate {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_deliver_sync",
+                "deliver_sync requires emit_after_ms=0 without emit_jitter_ms, emit_delay_model or process_after_ms",
+            ));
+        }
+    }
+
+    if let Some(profile) = &payload.sink_attempt_profile {
+        state.require_test_mode("sink_attempt_profile")?;
+        let valid = profile.len() <= MAX_SINK_PROFILE_STEPS
+            && profile.iter().all(|step| (200..=599).contains(&step.status) && step.delay_ms <= MAX_SINK_DELAY_MS);
+        if !valid {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_sink_attempt_profile",
+                format!(
+                    "sink_attempt_profile takes at most {} steps, each with a status of 200-599 and delay_ms up to {}",
+                    MAX_SINK_PROFILE_STEPS, MAX_SINK_DELAY_MS
+                ),
+            ));
+        }
+    }
+
+    if let Some(template) = &payload.webhook_body_template {
+        BodyTemplate::parse(template).map_err(|reason| {
+            ApiError::new(StatusCode::BAD_REQUEST, "invalid_webhook_body_template", reason)
+        })?;
+    }
+
+    state.metadata_limits.check(&payload.metadata)?;
+
+    if let Some(locale) = payload.locale.as_deref().filter(|l| locale_format(l).is_none()) {
+        warn!(locale, "unsupported invoice locale; using the server default");
+    }
+    if let Some(tz) = payload.tz.as_deref().filter(|tz| parse_utc_offset(tz).is_none()) {
+        warn!(tz, "invalid invoice tz; using the server default");
+    }
+    if payload.webhook_accept.as_deref().is_some_and(|v| axum::http::HeaderValue::from_str(v).is_err()) {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_webhook_accept",
+            "webhook_accept is not a valid header value",
+        ));
+    }
+
+    if let Some(order) = &payload.webhook_field_order {
+        if let Err(reason) = validate_field_order(order) {
+            return Err(ApiError::new(StatusCode::BAD_REQUEST, "invalid_webhook_field_order", reason));
+        }
+        if payload.webhook_body_template.is_some() {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_webhook_field_order",
+                "webhook_field_order applies to the standard payload and can't be combined with webhook_body_template",
+            ));
+        }
+    }
+
+    if payload.expire_to_status.as_ref().is_some_and(|s| !EXPIRY_STATUSES.contains(s)) {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_expire_to_status",
+            "expire_to_status must be expired or canceled",
+        ));
+    }
+
+    if payload.auth_amount.is_some_and(|hold| hold < payload.amount) {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_auth_amount",
+            "auth_amount must be greater than or equal to amount",
+        ));
+    }
+
+    if payload.verify_webhook_url && !state.verified_urls.contains_key(&payload.webhook_url) {
+        if let Err(reason) = run_verification_handshake(&state, &payload.webhook_url).await {
+            info!(url = %payload.webhook_url, %reason, "webhook url verification failed");
+            return Err(ApiError::new(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "webhook_verification_failed",
+                format!("Webhook URL verification failed: {}", reason),
+            ));
+        }
+        state.verified_urls.insert(payload.webhook_url.clone(), Utc::now());
+    }
+
+    let id = state.id_strategy.generate();
+    let now = Utc::now();
+
+    let seed = payload.seed.unwrap_or_else(|| state.sim_rng.lock().unwrap().random());
+    let mut rng = StdRng::seed_from_u64(seed);
+    let final_status = map_emit_status(&payload.emit_status, &mut rng);
+    let jitter_ms = if payload.emit_jitter_ms > 0 { rng.random_range(0..=payload.emit_jitter_ms) } else { 0 };
+    // Drawn last so seeds from before delay models still reproduce.
+    let sampled_delay_ms = payload.emit_delay_model.map(|model| model.sample(payload.emit_after_ms, &mut rng));
+    let emit_after_ms = sampled_delay_ms.unwrap_or(payload.emit_after_ms);
+    let randomized = matches!(payload.emit_status, EmitStatus::Random) || payload.emit_jitter_ms > 0 || sampled_delay_ms.is_some();
+    let simulation = randomized.then(|| Simulation {
+        seed,
+        chosen_outcome: final_status.clone(),
+        applied_jitter_ms: jitter_ms,
+        sampled_delay_ms,
+    });
+
+    // Applied after `simulation`, which keeps recording the drawn outcome.
+    let final_status = match final_status {
+        InvoiceStatus::Expired => payload.expire_to_status.clone().unwrap_or(InvoiceStatus::Expired),
+        other => other,
+    };
+
+    let requires_action = payload.require_3ds.then(|| RequiresAction {
+        kind: "3ds_challenge".into(),
+        challenge_url: format!("{}/3ds", state.checkout_url(&id)),
+    });
+
+    let mut invoice = Invoice {
+        id: id.clone(),
+        amount: payload.amount,
+        currency: payload.currency.clone(),
+        status: InvoiceStatus::Created,
+        webhook_url: payload.webhook_url.clone(),
+        webhook_urls: payload.webhook_urls.clone().unwrap_or_default(),
+        webhook_delivery_mode: payload.webhook_urls.is_some().then(|| payload.webhook_delivery_mode.unwrap_or_default()),
+        created_at: now,
+        updated_at: now,
+        metadata: payload.metadata.clone(),
+        external_id: payload.external_id.clone(),
+        consumer: payload.consumer.clone(),
+        tenant: tenant.clone(),
+        decline_code: payload.decline_code,
+        simulation: simulation.clone(),
+        webhook_chunked: payload.webhook_chunked,
+        webhook_accept: payload.webhook_accept.clone(),
+        webhook_expect_continue: payload.webhook_expect_continue,
+        enrichment,
+        locale: payload.locale.clone().filter(|l| locale_format(l).is_some()),
+        tz: payload.tz.clone().filter(|tz| parse_utc_offset(tz).is_some()),
+        require_signature_echo: payload.require_signature_echo,
+        emit_only_if_amount_gte: payload.emit_only_if_amount_gte,
+        webhook_deadline_ms: payload.webhook_deadline_ms,
+        max_retries: payload.max_retries.map(|n| n.min(state.retry.max_retries_ceiling)),
+        auth_amount: payload.auth_amount,
+        sign_timestamp_offset_secs: payload.sign_timestamp_offset_secs,
+        requires_action: requires_action.clone(),
+        risk_score: payload.risk_score,
+        review_resume_status: None,
+        captured_amount: None,
+        capture_expires_in_ms: payload.capture_expires_in_ms,
+        expire_to_status: payload.expire_to_status.clone(),
+        capture_expires_at: None,
+        next_emit_at: None,
+        process_after_ms: payload.process_after_ms,
+        sink_attempt_profile: payload.sink_attempt_profile.clone(),
+        batch_id: None,
+        scheduled_emit: None,
+        closed_at: None,
+        expired_reason: None,
+        emit_on_view: payload.emit_on_view,
+        viewed_at: None,
+        webhook_body_template: payload.webhook_body_template.clone(),
+        webhook_field_order: payload.webhook_field_order.clone(),
+        history: Vec::new(),
+        notes: Vec::new(),
+    };
+    // With content dedup, the fingerprint entry stays locked until the invoice
+    // is stored, so a concurrent identical create waits and then finds it.
+    let fingerprint = state.dedup_window.map(|window| {
+        let key = hex::encode(Sha256::digest(
+            serde_json::to_vec(&(payload.amount, &payload.currency, &payload.webhook_url, &payload.metadata))
+                .unwrap_or_default(),
+        ));
+        (state.content_fingerprints.entry(key), window)
+    });
+    if let Some((dashmap::mapref::entry::Entry::Occupied(entry), window)) = &fingerprint {
+        let (existing_id, at) = entry.get();
+        let existing = state.invoices.get(existing_id).filter(|_| now - *at < *window).map(|inv| inv.clone());
+        if let Some(existing) = existing {
+            info!(id = %existing.id, "identical create within the dedup window; returning the existing invoice");
+            let mut resp_headers = HeaderMap::new();
+            resp_headers.insert(axum::http::header::CONTENT_LOCATION, state.invoice_location(&existing.id));
+            resp_headers.insert("X-Deduplicated", axum::http::HeaderValue::from_static("true"));
+            let amount_formatted = format_amount(existing.amount, &existing.currency, state.formatting(&existing).0);
+            let resp = CreateInvoiceResponse {
+                id: existing.id.clone(),
+                status: existing.status,
+                amount: existing.amount,
+                amount_formatted,
+                currency: existing.currency,
+                created_at: existing.created_at,
+                webhook_url: existing.webhook_url,
+                checkout_url: state.checkout_url(&existing.id),
+                metadata: existing.metadata,
+                external_id: existing.external_id,
+                simulation: existing.simulation,
+                requires_action: existing.requires_action,
+                delivery: None,
+            };
+            let body = axum::body::Bytes::from(serde_json::to_vec(&resp).unwrap_or_default());
+            return Ok(json_bytes(StatusCode::OK, resp_headers, body));
+        }
+    }
+
+    // Claimed atomically with the entry API so two concurrent creates can't
+    // both win.
+    if let Some(ext) = &payload.external_id {
+        match state.external_ids.entry(ext.clone()) {
+            dashmap::mapref::entry::Entry::Occupied(existing) => {
+                return Err(ApiError::new(
+                    StatusCode::CONFLICT,
+                    "external_id_conflict",
+                    format!("An invoice with external_id {} already exists", ext),
+                )
+                .with_detail("invoice_id", serde_json::json!(existing.get())));
+            }
+            dashmap::mapref::entry::Entry::Vacant(slot) => {
+                slot.insert(id.clone());
+            }
+        }
+    }
+
+    // Last, so a create rejected for any other reason doesn't use up quota;
+    // the external_id claimed above is released if this one fails.
+    if let Some(tenant) = &tenant {
+        if let Err(err) = state.consume_tenant_quota(tenant, now) {
+            if let Some(ext) = &payload.external_id {
+                state.external_ids.remove_if(ext, |_, claimed| *claimed == id);
+            }
+            return Err(err);
+        }
+    }
+
+    // Only once nothing can deduplicate or reject the create: the transition
+    // goes into the global event log and takes a seq.
+    let initial_status = if payload.process_after_ms.is_some() { InvoiceStatus::Queued } else { InvoiceStatus::Created };
+    state.apply_transition(&mut invoice, initial_status.clone());
+    let delay = state.scaled(Duration::from_millis(emit_after_ms.saturating_add(jitter_ms)));
+    let wait = payload.process_after_ms.map(|ms| state.scaled(Duration::from_millis(ms)));
+    invoice.next_emit_at = Some(now + wait.unwrap_or(delay));
+    let scheduled_status = if payload.require_3ds { InvoiceStatus::PendingAuthentication } else { final_status };
+    // Review comes first; approving it carries on to what was scheduled.
+    let held_for_review = payload.risk_score.zip(state.risk_review_threshold).is_some_and(|(score, max)| score > max);
+    let scheduled_status = if held_for_review {
+        invoice.review_resume_status = Some(scheduled_status);
+        InvoiceStatus::Review
+    } else {
+        scheduled_status
+    };
+    invoice.scheduled_emit = Some(ScheduledEmit {
+        status: scheduled_status.clone(),
+        delay_ms: emit_after_ms.saturating_add(jitter_ms),
+        notify_on_process: payload.emit_queued_webhook,
+    });
+
+    let amount_formatted = format_amount(payload.amount, &payload.currency, state.formatting(&invoice).0);
+    state.invoices.insert(id.clone(), invoice.clone());
+    state.counters.invoices_created.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    if let Some((entry, window)) = fingerprint {
+        let key = entry.key().clone();
+        entry.insert((id.clone(), now));
+        // Forget the fingerprint once its window is over, unless a later
+        // create has taken it since.
+        let state = state.clone();
+        let expected = id.clone();
+        tokio::spawn(async move {
+            sleep(window.to_std().unwrap_or_default()).await;
+            state.content_fingerprints.remove_if(&key, |_, (id, _)| *id == expected);
+        });
+    }
+
+    // Schedule webhook
+    let mut delivery = None;
+    match wait {
+        Some(wait) => {
+            if payload.emit_queued_webhook {
+                let state = state.clone();
+                tokio::spawn(async move { emit_event(&state, &invoice, "invoice.queued").await; });
+            }
+            tokio::spawn(process_queued(state.clone(), id.clone(), wait, payload.emit_queued_webhook, delay, scheduled_status));
+        }
+        None if payload.deliver_sync => {
+            delivery = Some(deliver_inline(&state, &id, scheduled_status).await);
+        }
+        None => {
+            tokio::spawn(emit_scheduled(state.clone(), id.clone(), delay, scheduled_status));
+        }
+    }
+    // After an inline delivery the invoice has already moved on.
+    let status = match &delivery {
+        Some(_) => state.invoices.get(&id).map_or(initial_status, |inv| inv.status.clone()),
+        None => initial_status,
+    };
+
+    let checkout_url = state.checkout_url(&id);
+    let mut resp_headers = HeaderMap::new();
+    resp_headers.insert(axum::http::header::LOCATION, state.invoice_location(&id));
+    let resp = CreateInvoiceResponse {
+        id: id.clone(),
+        status,
+        amount: payload.amount,
+        amount_formatted,
+        currency: payload.currency,
+        created_at: now,
+        webhook_url: payload.webhook_url,
+        checkout_url,
+        metadata: payload.metadata,
+        external_id: payload.external_id,
+        simulation,
+        requires_action,
+        delivery,
+    };
+    let body = axum::body::Bytes::from(serde_json::to_vec(&resp).unwrap_or_default());
+
+    if let Some(key) = idempotency_key {
+        state.idempotency.insert(state.idempotency_scope("create", &key), IdempotencyEntry {
+            invoice_id: id,
+            body_hash,
+            status: StatusCode::CREATED,
+            response: body.clone(),
+        });
+    }
+
+    Ok(json_bytes(StatusCode::CREATED, resp_headers, body))
+}
+
+/// Bins invoices by `created_at` into the last `window` buckets, in one pass
+/// over the store.
+async fn invoice_report(State(state): State<AppState>, Query(q): Query<ReportQuery>) -> Result<Json<InvoiceReport>, ApiError> {
+    if q.window == 0 || q.window > MAX_REPORT_WINDOW {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_window",
+            format!("window must be between 1 and {}", MAX_REPORT_WINDOW),
+        ));
+    }
+    let size = q.bucket.seconds();
+    let now = Utc::now();
+    let first_start = now.timestamp().div_euclid(size) * size - (q.window as i64 - 1) * size;
+    let starts = (0..q.window as i64).map(|i| DateTime::from_timestamp(first_start + i * size, 0).unwrap_or(now));
+    let mut rows: Vec<ReportRow> = starts
+        .map(|start| ReportRow {
+            start,
+            count: 0,
+            totals: std::collections::BTreeMap::new(),
+            by_status: std::collections::BTreeMap::new(),
+        })
+        .collect();
+
+    for entry in state.invoices.iter() {
+        let inv = entry.value();
+        let offset = inv.created_at.timestamp() - first_start;
+        if offset < 0 {
+            continue;
+        }
+        let Some(row) = rows.get_mut((offset / size) as usize) else { continue };
+        row.count += 1;
+        *row.totals.entry(inv.currency.clone()).or_default() += inv.amount;
+        let status = serde_json::to_value(&inv.status).ok().and_then(|v| v.as_str().map(str::to_string)).unwrap_or_default();
+        *row.by_status.entry(status).or_default() += 1;
+    }
+
+    Ok(Json(InvoiceReport {
+        bucket: q.bucket,
+        window: q.window,
+        from: rows[0].start,
+        to: now,
+        buckets: rows,
+    }))
+}
+
+async fn get_invoice(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    peer: Option<ConnectInfo<SocketAddr>>,
+) -> Result<Json<Invoice>, ApiError> {
+    let id = state.parse_invoice_id(&id)?;
+    let inv = state.invoices.get(&id).map(|inv| inv.clone());
+    // Simulated read-after-write lag: too-young invoices look missing.
+    let lagging = inv.as_ref().and_then(|inv| {
+        let age = Utc::now() - inv.created_at;
+        (state.test_mode && age < state.get_lag).then(|| state.get_lag - age)
+    });
+    match (inv, lagging) {
+        (Some(_), Some(remaining)) if state.get_lag_status == StatusCode::TOO_EARLY => {
+            Err(ApiError::new(StatusCode::TOO_EARLY, "invoice_not_ready", format!("Invoice {} is not readable yet", id))
+                .with_detail("retry_after_ms", remaining.num_milliseconds()))
+        }
+        (Some(inv), None) => {
+            record_view(&state, &id, "api", &headers, peer.map(|ConnectInfo(addr)| addr));
+            Ok(Json(inv))
+        }
+        _ => Err(ApiError::new(StatusCode::NOT_FOUND, "invoice_not_found", format!("Invoice {} not found", id))),
+    }
+}
+
+/// Full `metadata` of an invoice, for webhooks that carried `metadata_url`.
+async fn get_metadata(State(state): State<AppState>, Path(id): Path<String>) -> Result<Json<serde_json::Value>, ApiError> {
+    let id = state.parse_invoice_id(&id)?;
+    let inv = state
+        .invoices
+        .get(&id)
+        .ok_or_else(|| ApiError::new(StatusCode::NOT_FOUND, "invoice_not_found", format!("Invoice {} not found", id)))?;
+    Ok(Json(inv.metadata.clone()))
+}
+
+/// Distinct event ids delivered (or attempted) for an invoice, in emission
+/// order, with how their delivery went.
+async fn events_emitted(State(state): State<AppState>, Path(id): Path<String>) -> Result<Json<Vec<EmittedEvent>>, ApiError> {
+    let id = state.parse_invoice_id(&id)?;
+    if !state.invoices.contains_key(&id) {
+        return Err(ApiError::new(StatusCode::NOT_FOUND, "invoice_not_found", format!("Invoice {} not found", id)));
+    }
+
+    let mut emitted: Vec<EmittedEvent> = Vec::new();
+    let multi_url = state.invoices.get(&id).is_some_and(|inv| !inv.webhook_urls.is_empty());
+    for record in state.deliveries.read().unwrap().iter().filter(|r| r.invoice_id == id) {
+        let Some(event_id) = &record.event_id else { continue };
+        let delivered_to = (multi_url && record.outcome == DeliveryOutcome::Delivered).then(|| record.url.clone());
+        match emitted.iter_mut().find(|e| &e.event_id == event_id) {
+            Some(e) => {
+                e.attempts += 1;
+                e.outcome = record.outcome;
+                e.status_code = record.status_code;
+                e.delivered_to.extend(delivered_to.filter(|url| !e.delivered_to.contains(url)));
+            }
+            None => emitted.push(EmittedEvent {
+                event_id: event_id.clone(),
+                event: record.event.clone(),
+                first_attempt_at: record.at,
+                attempts: 1,
+                max_retries: record.max_retries,
+                outcome: record.outcome,
+                status_code: record.status_code,
+                delivered_to: delivered_to.into_iter().collect(),
+            }),
+        }
+    }
+    Ok(Json(emitted))
+}
+
+/// `GET /invoice/:id` (with `UI_ENABLED=true`). `?outcome=abandon` (test mode)
+/// emits `invoice.checkout.abandoned` and leaves the invoice `Created`.
+async fn checkout_page(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Query(q): Query<CheckoutQuery>,
+    headers: HeaderMap,
+    peer: Option<ConnectInfo<SocketAddr>>,
+) -> Result<axum::response::Html<String>, ApiError> {
+    let id = state.parse_invoice_id(&id)?;
+    if q.outcome.is_some() {
+        state.require_test_mode("outcome")?;
+    }
+    let inv = state.invoices.get(&id).map(|inv| inv.clone()).ok_or_else(|| {
+        ApiError::new(StatusCode::NOT_FOUND, "invoice_not_found", format!("Invoice {} not found", id))
+    })?;
+    record_view(&state, &id, "checkout", &headers, peer.map(|ConnectInfo(addr)| addr));
+
+    let notice = match q.outcome {
+        Some(CheckoutOutcome::Abandon) => {
+            if inv.status != InvoiceStatus::Created {
+                return Err(ApiError::new(
+                    StatusCode::CONFLICT,
+                    "invoice_not_pending",
+                    format!("Invoice {} is {:?} and can no longer be abandoned", id, inv.status),
+                ));
+            }
+            info!(%id, "checkout abandoned");
+            let emitted = inv.clone();
+            let state = state.clone();
+            tokio::spawn(async move {
+                emit_event(&state, &emitted, "invoice.checkout.abandoned").await;
+            });
+            "<p><em>Checkout abandonado.</em></p>"
+        }
+        None => "",
+    };
+
+    Ok(axum::response::Html(format!(
+        "<!doctype html>\n<html><head><meta charset=\"utf-8\"><title>Checkout {id}</title></head>\n\
+         <body><h1>{amount}</h1><p>Invoice <code>{id}</code> &mdash; {status:?}</p>{notice}</body></html>\n",
+        id = inv.id,
+        amount = format_amount(inv.amount, &inv.currency, state.formatting(&inv).0),
+        status = inv.status,
+        notice = notice,
+    )))
+}
+
+/// Test mode: creates `count` invoices with random amounts, weighted currencies
+/// and random outcomes, all delivering to `/_sink`. Draws from the server RNG,
+/// so `SIMULATION_SEED` makes the batch reproducible.
+async fn seed_invoices(
+    State(state): State<AppState>,
+    Query(q): Query<SeedQuery>,
+) -> Result<(StatusCode, Json<serde_json::Value>), ApiError> {
+    state.require_test_mode("/_seed")?;
+    if q.count == 0 || q.count > MAX_SEED_COUNT {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_count",
+            format!("count must be between 1 and {}", MAX_SEED_COUNT),
+        ));
+    }
+
+    let payloads: Vec<serde_json::Value> = {
+        let mut rng = state.sim_rng.lock().unwrap();
+        let currencies = rand::distr::weighted::WeightedIndex::new(SEED_CURRENCY_WEIGHTS.iter().map(|(_, w)| *w))
+            .expect("non-empty currency weights");
+        (0..q.count)
+            .map(|i| {
+                let currency = SEED_CURRENCY_WEIGHTS[rng.sample(&currencies)].0;
+                let mut metadata = serde_json::json!({ "seeded": true, "index": i });
+                // Demo invoices still have to pass `REQUIRED_METADATA_KEYS`.
+                for key in &state.metadata_limits.required_keys {
+                    metadata[key.as_str()] = "seed".into();
+                }
+                serde_json::json!({
+                    "amount": rng.random_range(100..=100_000u64),
+                    "currency": currency,
+                    "webhook_url": format!("{}/_sink/consumer-{}", state.self_base_url, rng.random_range(1..=3)),
+                    "emit_after_ms": rng.random_range(0..=10_000u64),
+                    "emit_status": "random",
+                    "seed": rng.random::<u64>(),
+                    "metadata": metadata,
+                })
+            })
+            .collect()
+    };
+
+    let mut ids = Vec::with_capacity(payloads.len());
+    for payload in payloads {
+        let payload: CreateInvoice = serde_json::from_value(payload).expect("seed payload matches CreateInvoice");
+        let (_, _, body) = create_invoice(State(state.clone()), HeaderMap::new(), JsonBody(payload)).await?;
+        let resp: serde_json::Value = serde_json::from_slice(&body).unwrap_or_default();
+        ids.push(resp["id"].clone());
+    }
+
+    info!(count = ids.len(), "seeded random invoices");
+    Ok((StatusCode::CREATED, Json(serde_json::json!({ "created": ids.len(), "ids": ids }))))
+}
+
+/// Headers whose values `/debug/echo` and `/admin/recent` never show.
+const REDACTED_HEADERS: &[&str] = &["authorization", "proxy-authorization", "cookie", "set-cookie", "x-api-key"];
+
+/// Whether a header carries a credential (or, like `X-Signature`, something
+/// derived from one) and has to be shown as `[redacted]`.
+fn is_sensitive_header(name: &str) -> bool {
+    let lower = name.to_ascii_lowercase();
+    REDACTED_HEADERS.contains(&lower.as_str())
+        || lower.contains("secret")
+        || lower.contains("token")
+        || lower.contains("signature")
+}
+
+/// Test mode + admin token: reflects the request back as JSON, to see what a
+/// client or proxy actually sent.
+async fn debug_echo(
+    State(state): State<AppState>,
+    method: axum::http::Method,
+    uri: axum::http::Uri,
+    headers: HeaderMap,
+    body: axum::body::Bytes,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    state.require_test_mode("/debug/echo")?;
+    state.require_admin(&headers)?;
+
+    let mut echoed = serde_json::Map::new();
+    for (name, value) in &headers {
+        let lower = name.as_str();
+        let value = if is_sensitive_header(lower) {
+            "[redacted]".to_string()
+        } else {
+            String::from_utf8_lossy(value.as_bytes()).into_owned()
+        };
+        // Repeated headers are joined the way HTTP would fold them.
+        match echoed.get_mut(lower) {
+            Some(serde_json::Value::String(existing)) => {
+                existing.push_str(", ");
+                existing.push_str(&value);
+            }
+            _ => {
+                echoed.insert(lower.to_string(), value.into());
+            }
+        }
+    }
+
+    Ok(Json(serde_json::json!({
+        "method": method.as_str(),
+        "path": uri.path(),
+        "query": uri.query(),
+        "headers": echoed,
+        "body": String::from_utf8_lossy(&body),
+        "body_bytes": body.len(),
+    })))
+}
+
+/// Test mode: accepts and discards any webhook, so seeded invoices have
+/// somewhere to deliver. Invoices with a `sink_attempt_profile` get their
+/// scripted response instead, picked by the attempt number of the event
+/// (counted per `X-Event-Id`); attempts past the end of the profile get `204`.
+async fn sink(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    body: axum::body::Bytes,
+) -> Result<axum::response::Response, ApiError> {
+    state.require_test_mode("/_sink")?;
+    let invoice_id = serde_json::from_slice::<serde_json::Value>(&body)
+        .ok()
+        .and_then(|v| v.get("id").and_then(|id| id.as_str()).map(|id| InvoiceId(id.to_string())));
+    let profile = invoice_id
+        .and_then(|id| state.invoices.get(&id).and_then(|inv| inv.sink_attempt_profile.clone()));
+    let event_id = headers.get("X-Event-Id").and_then(|v| v.to_str().ok());
+    let step = match (profile, event_id) {
+        (Some(profile), Some(event_id)) => {
+            let attempt = {
+                let mut count = state.sink_attempts.entry(event_id.to_string()).or_insert(0);
+                *count += 1;
+                *count
+            };
+            profile.get(attempt as usize - 1).cloned()
+        }
+        _ => None,
+    };
+    // Always cooperates with `require_signature_echo`.
+    let echo = headers.get("X-Signature").cloned();
+    let with_echo = |mut resp: axum::response::Response| {
+        if let Some(sig) = echo {
+            resp.headers_mut().insert("X-Signature-Echo", sig);
+        }
+        resp
+    };
+    let Some(step) = step else {
+        return Ok(with_echo(StatusCode::NO_CONTENT.into_response()));
+    };
+
+    if step.delay_ms > 0 {
+        sleep(Duration::from_millis(step.delay_ms)).await;
+    }
+    let status = StatusCode::from_u16(step.status).unwrap_or(StatusCode::NO_CONTENT);
+    let mut resp = status.into_response();
+    if let Some(secs) = step.retry_after_secs {
+        resp.headers_mut().insert(axum::http::header::RETRY_AFTER, axum::http::HeaderValue::from(secs));
+    }
+    Ok(with_echo(resp))
+}
+
+/// Prometheus text exposition of the live gauges.
+async fn metrics(State(state): State<AppState>) -> impl IntoResponse {
+    let mut outstanding: Vec<(String, usize)> = state.outstanding.iter().map(|e| (e.key().clone(), *e.value())).collect();
+    outstanding.sort();
+    let mut out = String::from(
+        "# HELP tickpay_webhook_outstanding Deliveries queued or in flight per webhook URL.\n\
+         # TYPE tickpay_webhook_outstanding gauge\n",
+    );
+    for (url, count) in outstanding {
+        out.push_str(&format!("tickpay_webhook_outstanding{{url=\"{}\"}} {}\n", prometheus_label(&url), count));
+    }
+    if state.strict_order_per_url {
+        let mut waiting: Vec<(String, usize)> =
+            state.url_turn_waiting.iter().map(|e| (e.key().clone(), *e.value())).collect();
+        waiting.sort();
+        out.push_str(
+            "# HELP tickpay_webhook_order_queue_depth Deliveries waiting behind an earlier one to the same URL (strict order).\n\
+             # TYPE tickpay_webhook_order_queue_depth gauge\n",
+        );
+        for (url, count) in waiting {
+            out.push_str(&format!("tickpay_webhook_order_queue_depth{{url=\"{}\"}} {}\n", prometheus_label(&url), count));
+        }
+    }
+    let load = |counter: &std::sync::atomic::AtomicU64| counter.load(std::sync::atomic::Ordering::Relaxed);
+    out.push_str(&format!(
+        "# HELP tickpay_invoices_created_total Invoices created since startup.\n\
+         # TYPE tickpay_invoices_created_total counter\n\
+         tickpay_invoices_created_total {}\n\
+         # HELP tickpay_webhook_deliveries_total Webhook delivery attempts since startup, by outcome.\n\
+         # TYPE tickpay_webhook_deliveries_total counter\n",
+        load(&state.counters.invoices_created),
+    ));
+    for outcome in DeliveryOutcome::ALL {
+        out.push_str(&format!(
+            "tickpay_webhook_deliveries_total{{outcome=\"{}\"}} {}\n",
+            outcome.as_str(),
+            load(&state.counters.deliveries[outcome as usize]),
+        ));
+    }
+    out.push_str(&format!(
+        "# HELP tickpay_http_requests_total Outgoing webhook, verification and enrichment requests since startup.\n\
+         # TYPE tickpay_http_requests_total counter\n\
+         tickpay_http_requests_total {}\n\
+         # HELP tickpay_http_connections_opened_total Connections opened by the outgoing HTTP client; the rest of the requests reused a pooled one.\n\
+         # TYPE tickpay_http_connections_opened_total counter\n\
+         tickpay_http_connections_opened_total {}\n",
+        load(&state.counters.http_requests),
+        load(&state.counters.http_connections_opened),
+    ));
+    ([(axum::http::header::CONTENT_TYPE, "text/plain; version=0.0.4; charset=utf-8")], out)
+}
+
+/// Escapes a Prometheus label value.
+fn prometheus_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+async fn readyz(State(state): State<AppState>) -> Json<serde_json::Value> {
+    let remaining = state.grace_until.map_or(Duration::ZERO, |until| until.saturating_duration_since(tokio::time::Instant::now()));
+    Json(serde_json::json!({
+        "ready": true,
+        "webhook_grace_remaining_ms": remaining.as_millis() as u64,
+    }))
+}
+
+async fn list_features(State(state): State<AppState>) -> impl IntoResponse {
+    use ring::signature::KeyPair;
+    let mut body = serde_json::json!({
+        "enabled": state.features.iter().collect::<Vec<_>>(),
+        "available": KNOWN_FEATURES,
+    });
+    // What a consumer needs to check `X-Signature-Ed25519`.
+    if let Some(key) = &state.ed25519_key {
+        body["ed25519_public_key"] = serde_json::json!(hex::encode(key.public_key().as_ref()));
+    }
+    Json(body)
+}
+
+/// Webhook hosts in use, with `X-Tenant-Id` narrowing it to that tenant's
+/// invoices.
+async fn list_webhook_urls(State(state): State<AppState>, headers: HeaderMap) -> impl IntoResponse {
+    let tenant = headers.get("x-tenant-id").and_then(|v| v.to_str().ok()).map(str::trim).filter(|t| !t.is_empty());
+    let mut by_host: std::collections::HashMap<String, WebhookHostUsage> = std::collections::HashMap::new();
+    let mut invoice_ids = std::collections::HashSet::new();
+    for entry in state.invoices.iter() {
+        let inv = entry.value();
+        if tenant.is_some_and(|t| inv.tenant.as_deref() != Some(t)) {
+            continue;
+        }
+        invoice_ids.insert(inv.id.clone());
+        let mut counted: Vec<String> = Vec::new();
+        for url in inv.webhook_targets() {
+            let host = webhook_host(url);
+            let usage = by_host.entry(host.clone()).or_insert_with(|| WebhookHostUsage {
+                host: host.clone(),
+                invoice_count: 0,
+                last_used_at: None,
+                urls: Vec::new(),
+            });
+            // An invoice counts once per host, however many of its URLs are there.
+            if !counted.contains(&host) {
+                usage.invoice_count += 1;
+                counted.push(host);
+            }
+            if !usage.urls.contains(url) {
+                usage.urls.push(url.clone());
+            }
+        }
+    }
+    // Last use is the latest delivery attempt, whatever its outcome.
+    for record in state.deliveries.read().unwrap().iter().filter(|r| invoice_ids.contains(&r.invoice_id)) {
+        if let Some(usage) = by_host.get_mut(&record.host) {
+            if usage.last_used_at.is_none_or(|at| record.at > at) {
+                usage.last_used_at = Some(record.at);
+            }
+        }
+    }
+
+    let mut hosts: Vec<WebhookHostUsage> = by_host.into_values().collect();
+    for h in hosts.iter_mut() {
+        h.urls.sort();
+    }
+    hosts.sort_by(|a, b| b.invoice_count.cmp(&a.invoice_count).then_with(|| a.host.cmp(&b.host)));
+    Json(serde_json::json!({ "hosts": hosts }))
+}
+
+async fn get_batch(State(state): State<AppState>, Path(id): Path<String>) -> Result<Json<SettlementBatch>, ApiError> {
+    match state.batches.get(&id) {
+        Some(batch) => Ok(Json(batch.clone())),
+        None => Err(ApiError::new(StatusCode::NOT_FOUND, "batch_not_found", format!("Batch {} not found", id))),
+    }
+}
+
+async fn update_invoice(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    JsonBody(payload): JsonBody<UpdateInvoice>,
+) -> Result<Json<Invoice>, ApiError> {
+    let id = state.parse_invoice_id(&id)?;
+    let mut inv = state.invoices.get_mut(&id).ok_or_else(|| {
+        ApiError::new(StatusCode::NOT_FOUND, "invoice_not_found", format!("Invoice {} not found", id))
+    })?;
+    if !matches!(inv.status, InvoiceStatus::Created | InvoiceStatus::Queued) {
+        return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            "invoice_not_pending",
+            format!("Invoice {} is {:?} and can no longer be updated", id, inv.status),
+        ));
+    }
+    if let Some(url) = payload.webhook_url {
+        check_grpc_url(&url)?;
+        // With `webhook_urls`, this replaces the primary.
+        if let Some(primary) = inv.webhook_urls.first_mut() {
+            *primary = url.clone();
+        }
+        inv.webhook_url = url;
+        inv.updated_at = Utc::now();
+    }
+    Ok(Json(inv.clone()))
+}
+
+/// Moves an invoice from `from` to `to`, or answers 409 if it is in any other status.
+fn transition_invoice(state: &AppState, id: &InvoiceId, from: InvoiceStatus, to: InvoiceStatus) -> Result<Invoice, ApiError> {
+    transition_invoice_with(state, id, from, to, |_| {})
+}
+
+/// Like `transition_invoice`, running `prepare` on the invoice right before the
+/// transition is applied.
+fn transition_invoice_with(
+    state: &AppState,
+    id: &InvoiceId,
+    from: InvoiceStatus,
+    to: InvoiceStatus,
+    prepare: impl FnOnce(&mut Invoice),
+) -> Result<Invoice, ApiError> {
+    let mut inv = state.invoices.get_mut(id).ok_or_else(|| {
+        ApiError::new(StatusCode::NOT_FOUND, "invoice_not_found", format!("Invoice {} not found", id))
+    })?;
+    if inv.status != from {
+        return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            "invalid_invoice_status",
+            format!("Invoice {} is {:?}; expected {:?}", id, inv.status, from),
+        )
+        .with_detail("status", serde_json::json!(inv.status)));
+    }
+    prepare(&mut inv);
+    state.apply_transition(&mut inv, to);
+    Ok(inv.clone())
+}
+
+async fn capture_invoice(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    OptionalJsonBody(payload): OptionalJsonBody<CaptureRequest>,
+) -> Result<(StatusCode, HeaderMap, axum::body::Bytes), ApiError> {
+    let id = state.parse_invoice_id(&id)?;
+    let payload = payload.unwrap_or_default();
+
+    // A replayed capture returns the original response, even if the invoice
+    // was disputed or charged back since.
+    let operation = format!("capture:{}", id);
+    let body_hash = hex::encode(Sha256::digest(serde_json::to_vec(&payload).unwrap_or_default()));
+    let idempotency_key = idempotency_key(&headers);
+    if let Some(key) = &idempotency_key {
+        if let Some((existing, resp_headers)) = state.idempotent_replay(&operation, key, &body_hash)? {
+            return Ok(json_bytes(existing.status, resp_headers, existing.response));
+        }
+    }
+    let (expired, amount) = {
+        let inv = state.invoices.get(&id).ok_or_else(|| {
+            ApiError::new(StatusCode::NOT_FOUND, "invoice_not_found", format!("Invoice {} not found", id))
+        })?;
+        let expired = match inv.status {
+            InvoiceStatus::Voided => true,
+            InvoiceStatus::Authorized => inv.capture_expires_at.is_some_and(|at| at <= Utc::now()),
+            _ => false,
+        };
+        (expired, inv.amount)
+    };
+    let capture_amount = payload.amount.unwrap_or(amount);
+    if capture_amount == 0 || capture_amount > amount {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_capture_amount",
+            format!("Capture amount must be between 1 and the invoice amount ({})", amount),
+        ));
+    }
+    if expired {
+        return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            "authorization_expired",
+            format!("Authorization for invoice {} expired before capture", id),
+        ));
+    }
+    // Set before the transition so the settlement batch sees the captured amount.
+    let inv = transition_invoice_with(&state, &id, InvoiceStatus::Authorized, InvoiceStatus::Paid, |inv| {
+        inv.captured_amount = Some(capture_amount);
+    })?;
+
+    info!(%id, captured = capture_amount, "authorization captured");
+    let body = axum::body::Bytes::from(serde_json::to_vec(&inv).unwrap_or_default());
+    if let Some(key) = idempotency_key {
+        state.idempotency.insert(state.idempotency_scope(&operation, &key), IdempotencyEntry {
+            invoice_id: id,
+            body_hash,
+            status: StatusCode::OK,
+            response: body.clone(),
+        });
+    }
+    tokio::spawn(async move {
+        emit_event(&state, &inv, "invoice.captured").await;
+    });
+    Ok(json_bytes(StatusCode::OK, HeaderMap::new(), body))
+}
+
+/// Settles a 3DS challenge: `success` pays the invoice, `fail` fails it.
+async fn authenticate_invoice(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Query(q): Query<AuthenticateQuery>,
+) -> Result<Json<Invoice>, ApiError> {
+    let id = state.parse_invoice_id(&id)?;
+    let outcome = q.outcome.ok_or_else(|| {
+        ApiError::new(StatusCode::BAD_REQUEST, "outcome_required", "Pass ?outcome=success or ?outcome=fail")
+    })?;
+    let to = match outcome {
+        AuthenticationOutcome::Success => InvoiceStatus::Paid,
+        AuthenticationOutcome::Fail => InvoiceStatus::Failed,
+    };
+    transition_invoice(&state, &id, InvoiceStatus::PendingAuthentication, to)?;
+    let inv = {
+        let mut inv = state.invoices.get_mut(&id).ok_or_else(|| {
+            ApiError::new(StatusCode::NOT_FOUND, "invoice_not_found", format!("Invoice {} not found", id))
+        })?;
+        inv.requires_action = None;
+        inv.updated_at = Utc::now();
+        inv.clone()
+    };
+
+    info!(%id, ?outcome, "3ds challenge resolved");
+    let emitted = inv.clone();
+    tokio::spawn(async move {
+        emit_event(&state, &emitted, "invoice.updated").await;
+    });
+    Ok(Json(inv))
+}
+
+/// Settles a manual review: `approve` resumes to the status the emit had
+/// scheduled, `decline` fails the invoice.
+async fn review_invoice(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Query(q): Query<ReviewQuery>,
+) -> Result<Json<Invoice>, ApiError> {
+    let id = state.parse_invoice_id(&id)?;
+    let decision = q.decision.ok_or_else(|| {
+        ApiError::new(StatusCode::BAD_REQUEST, "decision_required", "Pass ?decision=approve or ?decision=decline")
+    })?;
+    let resume = state.invoices.get(&id).and_then(|inv| inv.review_resume_status.clone());
+    let to = match decision {
+        ReviewDecision::Approve => resume.unwrap_or(InvoiceStatus::Paid),
+        ReviewDecision::Decline => InvoiceStatus::Failed,
+    };
+    let inv = transition_invoice_with(&state, &id, InvoiceStatus::Review, to, |inv| {
+        inv.review_resume_status = None;
+    })?;
+
+    info!(%id, ?decision, status = ?inv.status, "manual review resolved");
+    if let (InvoiceStatus::Authorized, Some(ms)) = (&inv.status, inv.capture_expires_in_ms) {
+        tokio::spawn(expire_authorization(state.clone(), id.clone(), state.scaled(Duration::from_millis(ms))));
+    }
+    let emitted = inv.clone();
+    tokio::spawn(async move {
+        emit_event(&state, &emitted, "invoice.updated").await;
+    });
+    Ok(Json(inv))
+}
+
+async fn open_dispute(State(state): State<AppState>, Path(id): Path<String>) -> Result<Json<Invoice>, ApiError> {
+    let id = state.parse_invoice_id(&id)?;
+    let inv = transition_invoice(&state, &id, InvoiceStatus::Paid, InvoiceStatus::DisputeOpened)?;
+
+    info!(%id, "dispute opened");
+    let emitted = inv.clone();
+    tokio::spawn(async move {
+        emit_event(&state, &emitted, "invoice.dispute.opened").await;
+    });
+    Ok(Json(inv))
+}
+
+/// Re-sends the invoice's whole history to its current `webhook_url`: one
+/// `invoice.redelivered` per transition, oldest first, each sent only after the
+/// previous one is done (retries included).
+async fn redeliver_all(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<(StatusCode, Json<RedeliverySummary>), ApiError> {
+    let id = state.parse_invoice_id(&id)?;
+    let inv = state.invoices.get(&id).map(|inv| inv.clone()).ok_or_else(|| {
+        ApiError::new(StatusCode::NOT_FOUND, "invoice_not_found", format!("Invoice {} not found", id))
+    })?;
+    let summary = RedeliverySummary {
+        invoice_id: id.clone(),
+        webhook_url: inv.webhook_url.clone(),
+        events: inv.history.len(),
     };
 
-    let cors = CorsLayer::new()
-        .allow_methods(Any)
-        .allow_headers(Any)
-        .allow_origin(Any);
+    info!(%id, events = summary.events, "redelivering invoice history");
+    tokio::spawn(async move {
+        for (i, transition) in inv.history.iter().enumerate() {
+            // The invoice as of this transition, so `status`, `seq` and
+            // `event_id` are the historical ones.
+            let mut view = inv.clone();
+            view.status = transition.to.clone();
+            view.history.truncate(i + 1);
+            send_event(&state, &view, "invoice.redelivered", None, Some(transition.at)).await;
+        }
+    });
+    Ok((StatusCode::ACCEPTED, Json(summary)))
+}
 
-    let app = Router::new()
-        .route("/invoices", post(create_invoice))
-        .route("/invoices/:id", get(get_invoice))
-        .with_state(state)
-        .layer(TraceLayer::new_for_http())
-        .layer(cors);
+async fn resolve_dispute(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Query(q): Query<DisputeResolveQuery>,
+) -> Result<Json<Invoice>, ApiError> {
+    let id = state.parse_invoice_id(&id)?;
+    let outcome = q.outcome.ok_or_else(|| {
+        ApiError::new(StatusCode::BAD_REQUEST, "outcome_required", "Pass ?outcome=won or ?outcome=lost")
+    })?;
+    let to = match outcome {
+        DisputeOutcome::Won => InvoiceStatus::Paid,
+        DisputeOutcome::Lost => InvoiceStatus::Chargeback,
+    };
+    let inv = transition_invoice(&state, &id, InvoiceStatus::DisputeOpened, to)?;
 
-    let port: u16 = std::env::var("PORT").ok().and_then(|v| v.parse().ok()).unwrap_or(8080);
-    let addr = SocketAddr::from(([0, 0, 0, 0], port));
-    let listener = TcpListener::bind(addr).await.expect("bind");
-    info!(addr = %listener.local_addr().unwrap(), "fake-acquirer listening");
-    axum::serve(listener, app)
-        .await
-        .expect("server");
+    info!(%id, ?outcome, "dispute resolved");
+    let emitted = inv.clone();
+    tokio::spawn(async move {
+        emit_event(&state, &emitted, "invoice.dispute.resolved").await;
+    });
+    Ok(Json(inv))
 }
 
-async fn create_invoice(
+async fn delivery_stats(State(state): State<AppState>, Query(q): Query<DeliveryStatsQuery>) -> Json<DeliveryStats> {
+    let since = Utc::now() - chrono::Duration::seconds(q.window_secs.min(i64::MAX as u64) as i64);
+    let mut stats = DeliveryStats {
+        window_secs: q.window_secs,
+        host: q.host.clone(),
+        delivered: 0,
+        failed: 0,
+        dead_lettered: 0,
+        superseded: 0,
+        success_ratio: None,
+    };
+
+    let log = state.deliveries.read().unwrap();
+    // Concurrent attempts land in the log out of `at` order, so the whole log
+    // is scanned instead of stopping at the first older record.
+    for rec in log.iter().filter(|r| r.at >= since) {
+        if q.host.as_deref().is_some_and(|h| h != rec.host) {
+            continue;
+        }
+        match rec.outcome {
+            DeliveryOutcome::Delivered => stats.delivered += 1,
+            DeliveryOutcome::Failed => stats.failed += 1,
+            DeliveryOutcome::DeadLettered => stats.dead_lettered += 1,
+            DeliveryOutcome::Superseded => stats.superseded += 1,
+        }
+    }
+    drop(log);
+
+    let finished = stats.delivered + stats.dead_lettered;
+    if finished > 0 {
+        stats.success_ratio = Some(stats.delivered as f64 / finished as f64);
+    }
+    Json(stats)
+}
+
+/// Usage against `TENANT_QUOTAS`, one row per configured tenant.
+async fn tenant_usage(State(state): State<AppState>) -> Json<Vec<TenantUsageReport>> {
+    let now = Utc::now();
+    let mut rows: Vec<TenantUsageReport> = state
+        .tenant_quotas
+        .iter()
+        .map(|(tenant, quota)| {
+            let mut usage = state.tenant_usage.get(tenant).map_or_else(|| TenantUsage::new(now), |u| *u);
+            usage.roll(now);
+            TenantUsageReport {
+                tenant: tenant.clone(),
+                rate_per_min: quota.rate_per_min,
+                daily_quota: quota.daily_quota,
+                used_this_minute: usage.minute_count,
+                used_today: usage.day_count,
+                minute_resets_at: usage.minute_resets_at(),
+                day_resets_at: usage.day_resets_at(),
+            }
+        })
+        .collect();
+    rows.sort_by(|a, b| a.tenant.cmp(&b.tenant));
+    Json(rows)
+}
+
+/// Admin token: live tail of what was sent, newest first.
+async fn recent_deliveries(
     State(state): State<AppState>,
     headers: HeaderMap,
-    Json(payload): Json<CreateInvoice>,
-) -> impl IntoResponse {
-    // Idempotency (optional)
-    if let Some(key) = headers.get("Idempotency-Key").and_then(|v| v.to_str().ok()).map(|s| s.to_string()) {
-        if let Some(existing_id) = state.idempotency.get(&key).map(|e| *e.value()) {
-            if let Some(inv) = state.invoices.get(&existing_id) {
-                let resp = CreateInvoiceResponse {
-                    id: inv.id,
-                    status: inv.status.clone(),
-                    amount: inv.amount,
-                    currency: inv.currency.clone(),
-                    created_at: inv.created_at,
-                    webhook_url: inv.webhook_url.clone(),
-                    checkout_url: format!("https://checkout.local/invoice/{}", inv.id),
-                    metadata: inv.metadata.clone(),
-                };
-                return (StatusCode::OK, Json(resp));
+    Query(q): Query<RecentQuery>,
+) -> Result<Json<Vec<RecentDelivery>>, ApiError> {
+    state.require_admin(&headers)?;
+    let recent = state.recent.lock().unwrap();
+    let limit = q.limit.unwrap_or(recent.len());
+    Ok(Json(recent.iter().rev().take(limit).cloned().collect()))
+}
+
+/// Quotes a CSV field when it contains a delimiter, quote or line break.
+fn csv_field(value: &str) -> std::borrow::Cow<'_, str> {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\"")).into()
+    } else {
+        value.into()
+    }
+}
+
+/// Pages through the delivery log in the order attempts were made. The log is
+/// append-only, so an attempt's position (`n`, counted from 1) makes a stable
+/// cursor; only the returned page is copied.
+async fn list_deliveries(
+    State(state): State<AppState>,
+    Query(page): Query<DeliveriesPageQuery>,
+    Query(filter): Query<DeliveryFilter>,
+) -> Result<Json<DeliveriesPage>, ApiError> {
+    let after = match page.cursor.as_deref() {
+        None | Some("") => 0,
+        Some(c) => parse_event_cursor(c).ok_or_else(|| {
+            ApiError::new(StatusCode::BAD_REQUEST, "invalid_cursor", format!("{} is not a valid deliveries cursor", c))
+        })?,
+    };
+    let limit = page.limit.clamp(1, MAX_EVENTS_LIMIT);
+
+    let log = state.deliveries.read().unwrap();
+    let start = (after as usize).min(log.len());
+    let mut deliveries = Vec::new();
+    let mut next_cursor = page.cursor;
+    for (n, record) in log.iter().enumerate().skip(start) {
+        let cursor = event_cursor(record.at, n as u64 + 1);
+        if filter.matches(record) {
+            deliveries.push(DeliveryEntry { cursor: cursor.clone(), record: record.clone() });
+        }
+        next_cursor = Some(cursor);
+        if deliveries.len() == limit {
+            break;
+        }
+    }
+    drop(log);
+
+    Ok(Json(DeliveriesPage { deliveries, next_cursor }))
+}
+
+/// Streams the delivery log as CSV, a batch at a time, so neither the log nor
+/// the output is ever copied whole.
+async fn deliveries_csv(State(state): State<AppState>, Query(q): Query<DeliveryFilter>) -> impl IntoResponse {
+    let header = "invoice_id,event,attempt,at,host,outcome,status_code,duration_ms,error,body_retry_match,retry_rule,signature_confirmed\n".to_string();
+    let rows = futures_util::stream::unfold(0usize, move |next| {
+        let chunk = {
+            let log = state.deliveries.read().unwrap();
+            (next < log.len()).then(|| {
+                let end = (next + CSV_BATCH_ROWS).min(log.len());
+                let mut out = String::new();
+                for rec in log[next..end].iter().filter(|r| q.matches(r)) {
+                    out.push_str(&format!(
+                        "{},{},{},{},{},{},{},{},{},{},{},{}\n",
+                        csv_field(&rec.invoice_id.0),
+                        csv_field(&rec.event),
+                        rec.attempt,
+                        rec.at.to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+                        csv_field(&rec.host),
+                        rec.outcome.as_str(),
+                        rec.status_code.map(|c| c.to_string()).unwrap_or_default(),
+                        rec.duration_ms,
+                        csv_field(rec.error.as_deref().unwrap_or("")),
+                        rec.body_retry_match.map(|m| m.to_string()).unwrap_or_default(),
+                        csv_field(rec.retry_rule.as_deref().unwrap_or("")),
+                        rec.signature_confirmed.map(|c| c.to_string()).unwrap_or_default(),
+                    ));
+                }
+                (out, end)
+            })
+        };
+        async move { chunk.map(|(out, end)| (Ok::<_, std::convert::Infallible>(out), end)) }
+    });
+    let body = futures_util::StreamExt::chain(futures_util::stream::once(async move { Ok(header) }), rows);
+
+    (
+        [
+            (axum::http::header::CONTENT_TYPE, "text/csv; charset=utf-8"),
+            (axum::http::header::CONTENT_DISPOSITION, "attachment; filename=\"deliveries.csv\""),
+        ],
+        axum::body::Body::from_stream(body),
+    )
+}
+
+/// Dumps every invoice (and optionally the delivery log) for `POST /admin/restore`.
+async fn take_snapshot(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(q): Query<SnapshotQuery>,
+) -> Result<Json<Snapshot>, ApiError> {
+    state.require_admin(&headers)?;
+    let mut invoices: Vec<Invoice> = state.invoices.iter().map(|e| e.value().clone()).collect();
+    invoices.sort_by(|a, b| a.created_at.cmp(&b.created_at).then_with(|| a.id.0.cmp(&b.id.0)));
+    let deliveries = q.include_deliveries.then(|| state.deliveries.read().unwrap().clone());
+    Ok(Json(Snapshot { taken_at: Utc::now(), invoices, deliveries }))
+}
+
+/// Loads a snapshot. Restored transitions are renumbered into the event log
+/// in time order, so `seq` stays unique and increasing per invoice, and the
+/// timers pending invoices were waiting on are re-armed.
+async fn restore_snapshot(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(q): Query<RestoreQuery>,
+    JsonBody(snapshot): JsonBody<Snapshot>,
+) -> Result<Json<RestoreSummary>, ApiError> {
+    state.require_admin(&headers)?;
+    let replace = matches!(q.mode, RestoreMode::Replace);
+    if replace {
+        state.invoices.clear();
+        state.external_ids.clear();
+        state.content_fingerprints.clear();
+        state.idempotency.clear();
+        state.sink_attempts.clear();
+    }
+
+    let mut skipped = Vec::new();
+    let mut invoices = Vec::new();
+    for mut inv in snapshot.invoices {
+        // Snapshots taken before `updated_at` existed.
+        inv.updated_at = inv.updated_at.max(inv.created_at);
+        let taken = state.invoices.contains_key(&inv.id)
+            || invoices.iter().any(|other: &Invoice| other.id == inv.id)
+            || inv.external_id.as_ref().is_some_and(|ext| state.external_ids.contains_key(ext));
+        if taken {
+            skipped.push(inv.id);
+        } else {
+            if let Some(ext) = &inv.external_id {
+                state.external_ids.insert(ext.clone(), inv.id.clone());
+            }
+            invoices.push(inv);
+        }
+    }
+
+    let mut order: Vec<(DateTime<Utc>, u64, usize, usize)> = invoices
+        .iter()
+        .enumerate()
+        .flat_map(|(i, inv)| inv.history.iter().enumerate().map(move |(j, t)| (t.at, t.seq, i, j)))
+        .collect();
+    order.sort();
+    {
+        let mut events = state.events.write().unwrap();
+        if replace {
+            events.clear();
+        }
+        for (at, _, i, j) in order {
+            let seq = events.len() as u64 + 1;
+            let inv = &mut invoices[i];
+            let transition = &mut inv.history[j];
+            transition.seq = seq;
+            events.push(InvoiceEvent {
+                cursor: event_cursor(at, seq),
+                seq,
+                at,
+                invoice_id: inv.id.clone(),
+                from: transition.from.clone(),
+                to: transition.to.clone(),
+            });
+        }
+    }
+
+    let mut deliveries = snapshot.deliveries.unwrap_or_default();
+    // Attempts for skipped invoices would be attributed to the existing ones.
+    deliveries.retain(|r| !skipped.contains(&r.invoice_id));
+    let delivery_count = deliveries.len();
+    {
+        let mut log = state.deliveries.write().unwrap();
+        if replace {
+            log.clear();
+        }
+        log.extend(deliveries);
+    }
+
+    let restored = invoices.len();
+    let mut rescheduled = 0;
+    for inv in invoices {
+        state.invoices.insert(inv.id.clone(), inv.clone());
+        if reschedule_restored(&state, &inv) {
+            rescheduled += 1;
+        }
+    }
+    info!(restored, skipped = skipped.len(), rescheduled, "snapshot restored");
+    Ok(Json(RestoreSummary { mode: q.mode, restored, skipped, rescheduled, deliveries: delivery_count }))
+}
+
+/// Re-arms what a restored invoice was waiting on: its queue processing, its
+/// scheduled emit or its capture expiry. Timers already past fire right away.
+fn reschedule_restored(state: &AppState, inv: &Invoice) -> bool {
+    let until = |at: Option<DateTime<Utc>>| at.map_or(Duration::ZERO, |at| (at - Utc::now()).to_std().unwrap_or_default());
+    match (&inv.status, &inv.scheduled_emit) {
+        (InvoiceStatus::Queued, Some(emit)) => {
+            let delay = state.scaled(Duration::from_millis(emit.delay_ms));
+            tokio::spawn(process_queued(
+                state.clone(),
+                inv.id.clone(),
+                until(inv.next_emit_at),
+                emit.notify_on_process,
+                delay,
+                emit.status.clone(),
+            ));
+            true
+        }
+        (InvoiceStatus::Created, Some(emit)) => {
+            tokio::spawn(emit_scheduled(state.clone(), inv.id.clone(), until(inv.next_emit_at), emit.status.clone()));
+            true
+        }
+        (InvoiceStatus::Authorized, _) if inv.capture_expires_at.is_some() => {
+            tokio::spawn(expire_authorization(state.clone(), inv.id.clone(), until(inv.capture_expires_at)));
+            true
+        }
+        _ => false,
+    }
+}
+
+async fn expire_pending(
+    State(state): State<AppState>,
+    Query(q): Query<ConfirmQuery>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    if !q.confirm {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "confirmation_required",
+            "Pass confirm=true to expire every pending invoice",
+        ));
+    }
+
+    {
+        let mut last = state.last_mass_expiry.lock().unwrap();
+        if let Some(at) = *last {
+            let elapsed = at.elapsed();
+            if elapsed < state.mass_expiry_min_interval {
+                let wait = state.mass_expiry_min_interval - elapsed;
+                return Err(ApiError::new(
+                    StatusCode::TOO_MANY_REQUESTS,
+                    "rate_limited",
+                    format!("Mass expiry ran recently; retry in {}s", wait.as_secs().max(1)),
+                ));
+            }
+        }
+        *last = Some(std::time::Instant::now());
+    }
+
+    let pending: Vec<(InvoiceId, InvoiceStatus)> = state.invoices
+        .iter()
+        .filter(|e| matches!(e.value().status, InvoiceStatus::Created))
+        .map(|e| (e.key().clone(), e.expire_to_status.clone().unwrap_or(InvoiceStatus::Expired)))
+        .collect();
+
+    let (mut expired, mut canceled) = (0u64, 0u64);
+    for (id, to) in pending {
+        if let Some(inv) = transition_pending(&state, &id, to) {
+            match inv.status {
+                InvoiceStatus::Canceled => canceled += 1,
+                _ => expired += 1,
             }
+            let state = state.clone();
+            tokio::spawn(async move {
+                emit_event(&state, &inv, "invoice.updated").await;
+            });
         }
     }
 
-    let id = Uuid::new_v4();
+    info!(expired, canceled, "force-expired pending invoices");
+    Ok(Json(serde_json::json!({ "expired": expired, "canceled": canceled })))
+}
+
+/// Takes every dead letter matching the filter out of the queue and sends it
+/// again through `deliver`, with a fresh retry budget. Progress is tracked
+/// under the returned job id; webhooks that fail again go back in the queue.
+async fn redrive_dead_letters(
+    State(state): State<AppState>,
+    Query(q): Query<ConfirmQuery>,
+    Query(filter): Query<DeadLetterFilter>,
+) -> Result<(StatusCode, Json<RedriveJob>), ApiError> {
+    if !q.confirm {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "confirmation_required",
+            "Pass confirm=true to redrive every matching dead letter",
+        ));
+    }
+
+    let letters: std::collections::VecDeque<DeadLetter> = {
+        let mut queue = state.dead_letters.lock().unwrap();
+        let (matching, kept) = queue.drain(..).partition(|letter| filter.matches(letter));
+        *queue = kept;
+        matching
+    };
     let now = Utc::now();
+    let job = RedriveJob {
+        id: format!("redrive_{}", Uuid::new_v4().simple()),
+        started_at: now,
+        requeued: letters.len() as u64,
+        delivered: 0,
+        failed: 0,
+        pending: letters.len() as u64,
+        finished_at: letters.is_empty().then_some(now),
+    };
+    state.redrives.insert(job.id.clone(), job.clone());
+    info!(job_id = %job.id, requeued = job.requeued, "redriving dead letters");
+
+    for letter in letters {
+        let state = state.clone();
+        let job_id = job.id.clone();
+        tokio::spawn(async move {
+            let record = deliver(&state, &letter.invoice_id, letter.event, &letter.url, letter.body, &letter.opts).await;
+            let Some(mut job) = state.redrives.get_mut(&job_id) else { return };
+            match record.outcome {
+                DeliveryOutcome::Delivered => job.delivered += 1,
+                _ => job.failed += 1,
+            }
+            job.pending -= 1;
+            if job.pending == 0 {
+                job.finished_at = Some(Utc::now());
+            }
+        });
+    }
+
+    let status = if job.requeued > 0 { StatusCode::ACCEPTED } else { StatusCode::OK };
+    Ok((status, Json(job)))
+}
+
+async fn get_redrive(State(state): State<AppState>, Path(id): Path<String>) -> Result<Json<RedriveJob>, ApiError> {
+    match state.redrives.get(&id) {
+        Some(job) => Ok(Json(job.clone())),
+        None => Err(ApiError::new(StatusCode::NOT_FOUND, "redrive_not_found", format!("Redrive {} not found", id))),
+    }
+}
+
+async fn ping_webhook(State(state): State<AppState>, JsonBody(payload): JsonBody<PingRequest>) -> Json<PingResponse> {
+    let id = InvoiceId(format!("ping_{}", Uuid::new_v4().simple()));
+    let body = serde_json::json!({
+        "event": "webhook.ping",
+        "id": id,
+        "emitted_at": Utc::now(),
+    })
+    .to_string();
 
-    let invoice = Invoice {
+    info!(url = %payload.webhook_url, %id, "sending webhook ping");
+    let opts = DeliveryOptions { secret: payload.secret, max_retries: Some(0), ..Default::default() };
+    let record = deliver(&state, &id, "webhook.ping", &payload.webhook_url, body, &opts).await;
+
+    Json(PingResponse {
         id,
-        amount: payload.amount,
-        currency: payload.currency.clone(),
-        status: InvoiceStatus::Created,
-        webhook_url: payload.webhook_url.clone(),
-        created_at: now,
-        metadata: payload.metadata.clone(),
+        delivered: record.outcome == DeliveryOutcome::Delivered,
+        status_code: record.status_code,
+        duration_ms: record.duration_ms,
+        error: record.error,
+    })
+}
+
+async fn events_since(
+    State(state): State<AppState>,
+    Query(q): Query<EventsSinceQuery>,
+) -> Result<Json<EventsPage>, ApiError> {
+    let after_seq = match q.cursor.as_deref() {
+        None | Some("") => 0,
+        Some(c) => parse_event_cursor(c).ok_or_else(|| {
+            ApiError::new(StatusCode::BAD_REQUEST, "invalid_cursor", format!("{} is not a valid events cursor", c))
+        })?,
+    };
+    let limit = q.limit.clamp(1, MAX_EVENTS_LIMIT);
+
+    let log = state.events.read().unwrap();
+    let start = (after_seq as usize).min(log.len());
+    let events: Vec<InvoiceEvent> = log[start..].iter().take(limit).cloned().collect();
+    drop(log);
+
+    let next_cursor = events.last().map(|e| e.cursor.clone()).or(q.cursor);
+    Ok(Json(EventsPage { events, next_cursor }))
+}
+
+/// Lists invoices by last modification, oldest first, for incremental sync:
+/// poll with the last `next_cursor` to get whatever changed since. An invoice
+/// that changes again moves behind the cursor and comes back on a later page.
+async fn list_invoices(
+    State(state): State<AppState>,
+    Query(q): Query<ListInvoicesQuery>,
+) -> Result<Json<InvoicesPage>, ApiError> {
+    let after = match q.cursor.as_deref() {
+        None | Some("") => None,
+        Some(c) => Some(parse_invoice_cursor(c).ok_or_else(|| {
+            ApiError::new(StatusCode::BAD_REQUEST, "invalid_cursor", format!("{} is not a valid invoices cursor", c))
+        })?),
     };
+    let limit = q.limit.clamp(1, MAX_EVENTS_LIMIT);
 
-    state.invoices.insert(id, invoice.clone());
+    // Sort just the keys; only the returned page is cloned.
+    let mut keys: Vec<(DateTime<Utc>, String)> = state
+        .invoices
+        .iter()
+        .map(|e| (e.updated_at, e.id.0.clone()))
+        .filter(|(at, _)| q.modified_after.is_none_or(|t| *at > t))
+        .filter(|key| after.as_ref().is_none_or(|a| key > a))
+        .collect();
+    keys.sort();
+    let mut invoices = Vec::new();
+    let mut next_cursor = q.cursor;
+    for (at, id) in keys {
+        let id = InvoiceId(id);
+        let Some(inv) = state.invoices.get(&id).map(|inv| inv.clone()) else { continue };
+        // The key, not `inv.updated_at`: a change made since the scan must not
+        // move the cursor past invoices that were sorted before it.
+        next_cursor = Some(invoice_cursor(at, &id));
+        invoices.push(inv);
+        if invoices.len() == limit {
+            break;
+        }
+    }
+    Ok(Json(InvoicesPage { invoices, next_cursor }))
+}
 
-    // Track idempotency
-    if let Some(key) = headers.get("Idempotency-Key").and_then(|v| v.to_str().ok()).map(|s| s.to_string()) {
-        state.idempotency.insert(key, id);
+async fn add_note(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    JsonBody(payload): JsonBody<CreateNote>,
+) -> Result<(StatusCode, Json<Note>), ApiError> {
+    let id = state.parse_invoice_id(&id)?;
+    let text = payload.text.trim();
+    if text.is_empty() {
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, "note_empty", "Note text must not be empty"));
+    }
+    if text.chars().count() > MAX_NOTE_CHARS {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "note_too_long",
+            format!("Notes are limited to {} characters", MAX_NOTE_CHARS),
+        ));
     }
 
-    // Schedule webhook
-    let delay = Duration::from_millis(payload.emit_after_ms);
-    let client = state.client.clone();
-    let secret = state.webhook_secret.clone();
-    let invoices = state.invoices.clone();
-    let final_status = map_emit_status(&payload.emit_status);
-    let webhook_url = payload.webhook_url.clone();
+    let mut inv = state.invoices.get_mut(&id).ok_or_else(|| {
+        ApiError::new(StatusCode::NOT_FOUND, "invoice_not_found", format!("Invoice {} not found", id))
+    })?;
+    if inv.notes.len() >= MAX_NOTES_PER_INVOICE {
+        return Err(ApiError::new(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "notes_limit_reached",
+            format!("Invoice {} already has {} notes", id, MAX_NOTES_PER_INVOICE),
+        ));
+    }
+    let note = Note { at: Utc::now(), text: text.to_string() };
+    inv.notes.push(note.clone());
+    inv.updated_at = note.at;
+    Ok((StatusCode::CREATED, Json(note)))
+}
 
-    tokio::spawn(async move {
-        sleep(delay).await;
-        let mut inv = match invoices.get(&id) {
-            Some(v) => v.clone(),
-            None => {
-                error!(%id, "invoice not found when emitting webhook");
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tower::ServiceExt;
+
+    /// A request received by a `Receiver`.
+    struct Hit {
+        path: String,
+        headers: HeaderMap,
+        body: axum::body::Bytes,
+    }
+
+    /// Local webhook consumer that records every request and answers `status`.
+    struct Receiver {
+        base_url: String,
+        hits: std::sync::Arc<std::sync::Mutex<Vec<Hit>>>,
+    }
+
+    impl Receiver {
+        async fn start(status: StatusCode) -> Self {
+            let hits: std::sync::Arc<std::sync::Mutex<Vec<Hit>>> = Default::default();
+            let recorded = hits.clone();
+            let app = Router::new().fallback(move |uri: axum::http::Uri, headers: HeaderMap, body: axum::body::Bytes| {
+                let recorded = recorded.clone();
+                async move {
+                    recorded.lock().unwrap().push(Hit { path: uri.path().to_string(), headers, body });
+                    status
+                }
+            });
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let base_url = format!("http://{}", listener.local_addr().unwrap());
+            tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+            Receiver { base_url, hits }
+        }
+
+        fn url(&self, path: &str) -> String {
+            format!("{}{}", self.base_url, path)
+        }
+
+        fn hit_count(&self) -> usize {
+            self.hits.lock().unwrap().len()
+        }
+    }
+
+    fn test_state() -> AppState {
+        let mut state = AppState::from_env();
+        state.test_mode = true;
+        state
+    }
+
+    async fn call(
+        state: &AppState,
+        method: &str,
+        uri: &str,
+        headers: &[(&str, &str)],
+        body: Option<&str>,
+    ) -> (StatusCode, HeaderMap, axum::body::Bytes) {
+        let mut req = axum::http::Request::builder().method(method).uri(uri);
+        if body.is_some() && !headers.iter().any(|(name, _)| name.eq_ignore_ascii_case("content-type")) {
+            req = req.header("content-type", "application/json");
+        }
+        for (name, value) in headers {
+            req = req.header(*name, *value);
+        }
+        let req = req.body(axum::body::Body::from(body.unwrap_or_default().to_string())).unwrap();
+        let resp = router(state.clone()).oneshot(req).await.unwrap();
+        let (parts, body) = resp.into_parts();
+        (parts.status, parts.headers, axum::body::to_bytes(body, usize::MAX).await.unwrap())
+    }
+
+    fn json(body: &[u8]) -> serde_json::Value {
+        serde_json::from_slice(body).unwrap()
+    }
+
+    /// Creates an invoice and returns its id.
+    async fn create(state: &AppState, body: serde_json::Value) -> InvoiceId {
+        let (status, _, resp) = call(state, "POST", "/invoices", &[], Some(&body.to_string())).await;
+        assert_eq!(status, StatusCode::CREATED, "{}", String::from_utf8_lossy(&resp));
+        state.parse_invoice_id(json(&resp)["id"].as_str().unwrap()).unwrap()
+    }
+
+    /// Polls `done` for up to five seconds.
+    async fn wait_until(mut done: impl FnMut() -> bool) {
+        for _ in 0..500 {
+            if done() {
                 return;
             }
+            sleep(Duration::from_millis(10)).await;
+        }
+        panic!("condition not met in time");
+    }
+
+    fn status_of(state: &AppState, id: &InvoiceId) -> InvoiceStatus {
+        state.invoices.get(id).unwrap().status.clone()
+    }
+
+    #[tokio::test]
+    async fn patched_webhook_url_is_used_by_the_pending_emit() {
+        let state = test_state();
+        let receiver = Receiver::start(StatusCode::OK).await;
+        let body = serde_json::json!({
+            "amount": 1000,
+            "emit_status": "paid",
+            "emit_after_ms": 300,
+            "webhook_url": receiver.url("/old"),
+        });
+        let id = create(&state, body).await;
+
+        let patch = serde_json::json!({ "webhook_url": receiver.url("/new") }).to_string();
+        let (status, _, _) = call(&state, "PATCH", &format!("/invoices/{}", id), &[], Some(&patch)).await;
+        assert_eq!(status, StatusCode::OK);
+
+        wait_until(|| receiver.hit_count() > 0).await;
+        let hits = receiver.hits.lock().unwrap();
+        assert!(hits.iter().all(|hit| hit.path == "/new"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn authorization_expires_after_capture_window() {
+        let state = test_state();
+        let receiver = Receiver::start(StatusCode::OK).await;
+        let body = serde_json::json!({
+            "amount": 1000,
+            "emit_status": "authorized",
+            "emit_after_ms": 0,
+            "capture_expires_in_ms": 60_000,
+            "webhook_url": receiver.url("/"),
+        });
+        let id = create(&state, body).await;
+        wait_until(|| status_of(&state, &id) == InvoiceStatus::Authorized).await;
+
+        // The paused clock jumps straight to the end of the capture window.
+        sleep(Duration::from_secs(61)).await;
+        wait_until(|| status_of(&state, &id) == InvoiceStatus::Voided).await;
+        wait_until(|| {
+            state.deliveries.read().unwrap().iter().any(|r| r.invoice_id == id && r.event == "invoice.authorization.expired")
+        })
+        .await;
+
+        let (status, _, resp) = call(&state, "POST", &format!("/invoices/{}/capture", id), &[], None).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(json(&resp)["error"], "authorization_expired");
+    }
+
+    #[tokio::test]
+    async fn create_sets_location_and_replays_set_content_location() {
+        let state = test_state();
+        let body = serde_json::json!({ "amount": 1000, "emit_status": "paid", "emit_after_ms": 60_000, "webhook_url": "http://127.0.0.1:9/" })
+            .to_string();
+        let headers = [("idempotency-key", "location-test")];
+
+        let (status, resp_headers, resp) = call(&state, "POST", "/invoices", &headers, Some(&body)).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let id = json(&resp)["id"].as_str().unwrap().to_string();
+        assert_eq!(resp_headers[axum::http::header::LOCATION], format!("/invoices/{}", id));
+        assert!(!resp_headers.contains_key(axum::http::header::CONTENT_LOCATION));
+
+        let (status, resp_headers, resp) = call(&state, "POST", "/invoices", &headers, Some(&body)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(json(&resp)["id"], id.as_str());
+        assert_eq!(resp_headers[axum::http::header::CONTENT_LOCATION], format!("/invoices/{}", id));
+        assert!(!resp_headers.contains_key(axum::http::header::LOCATION));
+    }
+
+    #[tokio::test]
+    async fn capture_rejects_malformed_bodies_and_captures_all_without_one() {
+        let mut state = test_state();
+        state.strict_content_type = true;
+        let body = serde_json::json!({ "amount": 1000, "emit_status": "authorized", "emit_after_ms": 0, "webhook_url": "http://127.0.0.1:9/" });
+        let id = create(&state, body).await;
+        wait_until(|| status_of(&state, &id) == InvoiceStatus::Authorized).await;
+        let uri = format!("/invoices/{}/capture", id);
+
+        let (status, _, resp) = call(&state, "POST", &uri, &[], Some(r#"{"amount":"#)).await;
+        assert_eq!((status, json(&resp)["error"].clone()), (StatusCode::BAD_REQUEST, "invalid_json".into()));
+        let (status, _, resp) = call(&state, "POST", &uri, &[], Some(r#"{"amount":"500"}"#)).await;
+        assert_eq!((status, json(&resp)["error"].clone()), (StatusCode::UNPROCESSABLE_ENTITY, "invalid_body".into()));
+        let (status, _, _) = call(&state, "POST", &uri, &[("content-type", "text/plain")], Some(r#"{"amount":500}"#)).await;
+        assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        assert_eq!(status_of(&state, &id), InvoiceStatus::Authorized);
+
+        let (status, _, resp) = call(&state, "POST", &uri, &[], None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(json(&resp)["captured_amount"], 1000);
+    }
+
+    #[tokio::test]
+    async fn large_metadata_integers_reach_the_webhook_unchanged() {
+        let state = test_state();
+        let receiver = Receiver::start(StatusCode::OK).await;
+        let body = format!(
+            r#"{{"amount":1000,"emit_status":"paid","emit_after_ms":0,"webhook_url":"{}","metadata":{{"ref":9007199254740993}}}}"#,
+            receiver.url("/")
+        );
+        let (status, _, resp) = call(&state, "POST", "/invoices", &[], Some(&body)).await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert!(String::from_utf8_lossy(&resp).contains(r#""metadata":{"ref":9007199254740993}"#));
+
+        wait_until(|| receiver.hit_count() > 0).await;
+        let hits = receiver.hits.lock().unwrap();
+        assert!(String::from_utf8_lossy(&hits[0].body).contains(r#""metadata":{"ref":9007199254740993}"#));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+    async fn transition_seqs_stay_monotonic_under_concurrency() {
+        const INVOICES: usize = 20;
+        const TRANSITIONS: usize = 200;
+        let state = test_state();
+        let mut ids = Vec::new();
+        for _ in 0..INVOICES {
+            let body = serde_json::json!({ "amount": 1000, "emit_status": "paid", "emit_after_ms": 3_600_000, "webhook_url": "http://127.0.0.1:9/" });
+            ids.push(create(&state, body).await);
+        }
+
+        let tasks: Vec<_> = ids
+            .iter()
+            .cloned()
+            .map(|id| {
+                let state = state.clone();
+                tokio::task::spawn_blocking(move || {
+                    for i in 0..TRANSITIONS {
+                        let to = if i % 2 == 0 { InvoiceStatus::Queued } else { InvoiceStatus::Created };
+                        let mut inv = state.invoices.get_mut(&id).unwrap();
+                        state.apply_transition(&mut inv, to);
+                    }
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        let events = state.events.read().unwrap();
+        assert_eq!(events.len(), INVOICES * (TRANSITIONS + 1));
+        assert!(events.iter().enumerate().all(|(i, e)| e.seq == i as u64 + 1));
+        for id in &ids {
+            let inv = state.invoices.get(id).unwrap();
+            assert_eq!(inv.history.len(), TRANSITIONS + 1);
+            assert!(inv.history.windows(2).all(|pair| pair[0].seq < pair[1].seq));
+            // The invoice's history is its slice of the global log, in order.
+            let logged: Vec<u64> = events.iter().filter(|e| &e.invoice_id == id).map(|e| e.seq).collect();
+            let history: Vec<u64> = inv.history.iter().map(|t| t.seq).collect();
+            assert_eq!(logged, history);
+        }
+    }
+
+    #[tokio::test]
+    async fn capture_replay_returns_the_original_response_after_a_dispute() {
+        let state = test_state();
+        let body = serde_json::json!({ "amount": 1000, "emit_status": "authorized", "emit_after_ms": 0, "webhook_url": "http://127.0.0.1:9/" });
+        let id = create(&state, body).await;
+        wait_until(|| status_of(&state, &id) == InvoiceStatus::Authorized).await;
+        let uri = format!("/invoices/{}/capture", id);
+        let headers = [("idempotency-key", "capture-once")];
+
+        let (status, _, original) = call(&state, "POST", &uri, &headers, Some(r#"{"amount":600}"#)).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _, _) = call(&state, "POST", &format!("/invoices/{}/dispute", id), &[], None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(status_of(&state, &id), InvoiceStatus::DisputeOpened);
+
+        let (status, _, replayed) = call(&state, "POST", &uri, &headers, Some(r#"{"amount":600}"#)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(replayed, original);
+        assert_eq!(json(&replayed)["status"], "paid");
+    }
+
+    #[tokio::test]
+    async fn hashed_idempotency_keys_resolve_to_the_same_entry() {
+        let mut state = test_state();
+        state.idempotency_hash_keys = true;
+        let key = "k".repeat(300);
+        let headers = [("idempotency-key", key.as_str())];
+        let body = serde_json::json!({ "amount": 1000, "emit_status": "paid", "emit_after_ms": 60_000, "webhook_url": "http://127.0.0.1:9/" })
+            .to_string();
+
+        let (status, _, first) = call(&state, "POST", "/invoices", &headers, Some(&body)).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let (status, _, replayed) = call(&state, "POST", "/invoices", &headers, Some(&body)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(replayed, first);
+
+        assert_eq!(state.invoices.len(), 1);
+        let hashed = hex::encode(Sha256::digest(key.as_bytes()));
+        assert!(state.idempotency.contains_key(&("create".to_string(), hashed)));
+        assert!(!state.idempotency.contains_key(&("create".to_string(), key.clone())));
+    }
+
+    #[tokio::test]
+    async fn retry_after_a_newer_terminal_transition_is_superseded() {
+        let mut state = test_state();
+        state.retry.base_delay = Duration::from_millis(300);
+        let receiver = Receiver::start(StatusCode::INTERNAL_SERVER_ERROR).await;
+        let body = serde_json::json!({
+            "amount": 1000,
+            "emit_status": "authorized",
+            "emit_after_ms": 0,
+            "max_retries": 3,
+            "webhook_url": receiver.url("/"),
+        });
+        let id = create(&state, body).await;
+        let attempts = |outcome: DeliveryOutcome| {
+            state
+                .deliveries
+                .read()
+                .unwrap()
+                .iter()
+                .filter(|r| r.invoice_id == id && r.event == "invoice.updated" && r.outcome == outcome)
+                .count()
         };
-        inv.status = final_status.clone();
-        invoices.insert(id, inv.clone());
+        // The `authorized` webhook failed once and waits for its retry.
+        wait_until(|| attempts(DeliveryOutcome::Failed) == 1).await;
 
-        let body = WebhookPayload {
-            event: "invoice.updated",
-            id: inv.id,
-            status: inv.status.clone(),
-            amount: inv.amount,
-            currency: inv.currency.clone(),
-            emitted_at: Utc::now(),
-            metadata: inv.metadata.clone(),
+        let (status, _, _) = call(&state, "POST", &format!("/invoices/{}/capture", id), &[], None).await;
+        assert_eq!(status, StatusCode::OK);
+
+        wait_until(|| attempts(DeliveryOutcome::Superseded) == 1).await;
+        assert_eq!(attempts(DeliveryOutcome::Failed), 1);
+    }
+
+    #[tokio::test]
+    async fn missing_webhook_url_needs_a_default() {
+        let mut state = test_state();
+        state.default_webhook_url = None;
+        let body = serde_json::json!({ "amount": 1000, "emit_status": "paid", "emit_after_ms": 60_000 }).to_string();
+
+        let (status, _, resp) = call(&state, "POST", "/invoices", &[], Some(&body)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(json(&resp)["error"], "webhook_url_required");
+
+        state.default_webhook_url = Some("http://127.0.0.1:9/default".into());
+        let (status, _, resp) = call(&state, "POST", "/invoices", &[], Some(&body)).await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(json(&resp)["webhook_url"], "http://127.0.0.1:9/default");
+    }
+
+    #[tokio::test]
+    async fn each_host_gets_signatures_from_its_own_secret() {
+        let mut state = test_state();
+        let first = Receiver::start(StatusCode::OK).await;
+        let second = Receiver::start(StatusCode::OK).await;
+        let host = |receiver: &Receiver| receiver.base_url.trim_start_matches("http://").to_string();
+        state.secrets_by_host = std::sync::Arc::new(
+            [(host(&first), "secret-a".to_string()), (host(&second), "secret-b".to_string())].into_iter().collect(),
+        );
+        for receiver in [&first, &second] {
+            let body = serde_json::json!({ "amount": 1000, "emit_status": "paid", "emit_after_ms": 0, "webhook_url": receiver.url("/") });
+            create(&state, body).await;
+        }
+        wait_until(|| first.hit_count() > 0 && second.hit_count() > 0).await;
+
+        for (receiver, secret, other) in [(&first, "secret-a", "secret-b"), (&second, "secret-b", "secret-a")] {
+            let hits = receiver.hits.lock().unwrap();
+            let body = std::str::from_utf8(&hits[0].body).unwrap();
+            let sig = hits[0].headers["x-signature"].to_str().unwrap();
+            assert_eq!(sig, hmac_hex(secret, body));
+            assert_ne!(sig, hmac_hex(other, body));
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn identical_concurrent_creates_collapse_into_one() {
+        let mut state = test_state();
+        state.dedup_window = Some(chrono::Duration::seconds(5));
+        let body = serde_json::json!({ "amount": 1000, "emit_status": "paid", "emit_after_ms": 60_000, "webhook_url": "http://127.0.0.1:9/" })
+            .to_string();
+
+        let (a, b) = tokio::join!(
+            call(&state, "POST", "/invoices", &[], Some(&body)),
+            call(&state, "POST", "/invoices", &[], Some(&body)),
+        );
+        let mut statuses = [a.0, b.0];
+        statuses.sort();
+        assert_eq!(statuses, [StatusCode::OK, StatusCode::CREATED]);
+        assert_eq!(json(&a.2)["id"], json(&b.2)["id"]);
+        let deduplicated = if a.0 == StatusCode::OK { &a.1 } else { &b.1 };
+        assert_eq!(deduplicated["x-deduplicated"], "true");
+        assert_eq!(state.invoices.len(), 1);
+        let (_, _, page) = call(&state, "GET", "/events/since", &[], None).await;
+        assert_eq!(json(&page)["events"].as_array().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn over_quota_create_leaves_no_event() {
+        let mut state = test_state();
+        state.tenant_quotas = std::sync::Arc::new(
+            [("billing".to_string(), TenantQuota { rate_per_min: None, daily_quota: Some(1) })].into_iter().collect(),
+        );
+        let headers = [("x-tenant-id", "billing")];
+        let body = serde_json::json!({ "amount": 1000, "emit_status": "paid", "emit_after_ms": 60_000, "webhook_url": "http://127.0.0.1:9/" })
+            .to_string();
+
+        let (status, _, _) = call(&state, "POST", "/invoices", &headers, Some(&body)).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let (status, _, resp) = call(&state, "POST", "/invoices", &headers, Some(&body)).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(json(&resp)["error"], "quota_exceeded");
+
+        let (_, _, page) = call(&state, "GET", "/events/since", &[], None).await;
+        assert_eq!(json(&page)["events"].as_array().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn conflicting_external_id_leaves_no_event() {
+        let state = test_state();
+        let body = serde_json::json!({
+            "amount": 1000,
+            "emit_status": "paid",
+            "emit_after_ms": 60_000,
+            "webhook_url": "http://127.0.0.1:9/",
+            "external_id": "order-1",
+        })
+        .to_string();
+
+        let (status, _, _) = call(&state, "POST", "/invoices", &[], Some(&body)).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let (status, _, resp) = call(&state, "POST", "/invoices", &[], Some(&body)).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(json(&resp)["error"], "external_id_conflict");
+
+        let (_, _, page) = call(&state, "GET", "/events/since", &[], None).await;
+        assert_eq!(json(&page)["events"].as_array().unwrap().len(), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+    async fn concurrent_creates_are_all_counted() {
+        const CREATES: usize = 200;
+        let state = test_state();
+        let tasks: Vec<_> = (0..CREATES)
+            .map(|i| {
+                let state = state.clone();
+                tokio::spawn(async move {
+                    let body = serde_json::json!({ "amount": 1000 + i, "emit_status": "paid", "emit_after_ms": 60_000, "webhook_url": "http://127.0.0.1:9/" });
+                    create(&state, body).await
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        assert_eq!(state.counters.invoices_created.load(std::sync::atomic::Ordering::Relaxed), CREATES as u64);
+        let (_, _, metrics) = call(&state, "GET", "/metrics", &[], None).await;
+        assert!(String::from_utf8_lossy(&metrics).contains(&format!("\ntickpay_invoices_created_total {}\n", CREATES)));
+    }
+
+    #[tokio::test]
+    async fn metadata_limits_reject_wide_and_deep_objects() {
+        let mut state = test_state();
+        state.metadata_limits.max_keys = Some(3);
+        state.metadata_limits.max_depth = Some(2);
+        let create_with = |metadata: serde_json::Value| {
+            serde_json::json!({ "amount": 1000, "emit_status": "paid", "emit_after_ms": 60_000, "webhook_url": "http://127.0.0.1:9/", "metadata": metadata })
+                .to_string()
         };
 
-        let json_body = match serde_json::to_string(&body) {
-            Ok(s) => s,
-            Err(e) => {
-                error!(error = %e, "serialize webhook body");
-                return;
-            }
+        let wide = create_with(serde_json::json!({ "a": 1, "b": 2, "c": 3, "d": 4 }));
+        let (status, _, resp) = call(&state, "POST", "/invoices", &[], Some(&wide)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(json(&resp)["error"], "metadata_too_many_keys");
+        assert_eq!(json(&resp)["path"], "metadata.d");
+
+        let deep = create_with(serde_json::json!({ "a": { "b": { "c": 1 } } }));
+        let (status, _, resp) = call(&state, "POST", "/invoices", &[], Some(&deep)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(json(&resp)["error"], "metadata_too_deep");
+        assert_eq!(json(&resp)["path"], "metadata.a.b");
+
+        let compliant = create_with(serde_json::json!({ "a": { "b": 1 }, "c": [1, 2] }));
+        let (status, _, _) = call(&state, "POST", "/invoices", &[], Some(&compliant)).await;
+        assert_eq!(status, StatusCode::CREATED);
+    }
+
+    #[tokio::test]
+    async fn create_requires_the_configured_metadata_keys() {
+        let mut state = test_state();
+        state.metadata_limits.required_keys = vec!["order_id".into(), "customer".into()];
+        let create_with = |metadata: serde_json::Value| {
+            serde_json::json!({ "amount": 1000, "emit_status": "paid", "emit_after_ms": 60_000, "webhook_url": "http://127.0.0.1:9/", "metadata": metadata })
+                .to_string()
         };
 
-        let sig = hmac_hex(&secret, &json_body);
+        let (status, _, resp) = call(&state, "POST", "/invoices", &[], Some(&create_with(serde_json::json!({ "order_id": "o-1" })))).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(json(&resp)["error"], "missing_metadata_keys");
+        assert_eq!(json(&resp)["missing"], serde_json::json!(["customer"]));
 
-        info!(url = %webhook_url, status = ?body.status, "emitting webhook");
+        let present = create_with(serde_json::json!({ "order_id": "o-1", "customer": "c-1" }));
+        let (status, _, _) = call(&state, "POST", "/invoices", &[], Some(&present)).await;
+        assert_eq!(status, StatusCode::CREATED);
+    }
 
-        let res = client
-            .post(&webhook_url)
-            .header("Content-Type", "application/json")
-            .header("X-Event", "invoice.updated")
-            .header("X-Signature", sig)
-            .body(json_body)
-            .send()
+    #[tokio::test]
+    async fn recent_deliveries_need_the_admin_token_and_hide_signatures() {
+        let mut state = test_state();
+        state.admin_token = Some("t".into());
+        let receiver = Receiver::start(StatusCode::OK).await;
+        create(&state, serde_json::json!({ "amount": 1000, "emit_status": "paid", "emit_after_ms": 0, "webhook_url": receiver.url("/") }))
             .await;
+        wait_until(|| !state.recent.lock().unwrap().is_empty()).await;
 
-        match res {
-            Ok(r) => info!(status = %r.status(), "webhook delivered"),
-            Err(e) => error!(error = %e, "webhook delivery failed"),
-        }
-    });
+        let (status, _, _) = call(&state, "GET", "/admin/recent", &[], None).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
 
-    let resp = CreateInvoiceResponse {
-        id,
-        status: InvoiceStatus::Created,
-        amount: payload.amount,
-        currency: payload.currency,
-        created_at: now,
-        webhook_url: payload.webhook_url,
-        checkout_url: format!("https://checkout.local/invoice/{}", id),
-        metadata: payload.metadata,
-    };
+        let (status, _, body) = call(&state, "GET", "/admin/recent", &[("authorization", "Bearer t")], None).await;
+        assert_eq!(status, StatusCode::OK);
+        let headers = &json(&body)[0]["headers"];
+        assert_eq!(headers["X-Signature"], "[redacted]");
+        assert_eq!(headers["X-Event"], "invoice.updated");
+    }
 
-    (StatusCode::CREATED, Json(resp))
-}
+    #[tokio::test]
+    async fn recent_deliveries_hide_the_bearer_jwt() {
+        let mut state = test_state();
+        state.admin_token = Some("t".into());
+        state.jwt = Some(JwtPlacement::Header);
+        let receiver = Receiver::start(StatusCode::OK).await;
+        create(&state, serde_json::json!({ "amount": 1000, "emit_status": "paid", "emit_after_ms": 0, "webhook_url": receiver.url("/") }))
+            .await;
+        wait_until(|| !state.recent.lock().unwrap().is_empty()).await;
+        let sent = receiver.hits.lock().unwrap()[0].headers["authorization"].clone();
+        assert!(sent.to_str().unwrap().starts_with("Bearer "));
 
-async fn get_invoice(State(state): State<AppState>, Path(id): Path<Uuid>) -> impl IntoResponse {
-    match state.invoices.get(&id) {
-        Some(inv) => (StatusCode::OK, Json(inv.clone())).into_response(),
-        None => (StatusCode::NOT_FOUND, Json(serde_json::json!({
-            "error": "invoice_not_found",
-            "message": format!("Invoice {} not found", id)
-        }))).into_response(),
+        let (status, _, body) = call(&state, "GET", "/admin/recent", &[("authorization", "Bearer t")], None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(json(&body)[0]["headers"]["Authorization"], "[redacted]");
     }
-}
\ No newline at end of file
+}